@@ -0,0 +1,178 @@
+use std::path::{Path, PathBuf};
+
+use tokio::{fs, io};
+use walkdir::WalkDir;
+
+use crate::{
+    neocities::Neocities,
+    state::{now, read_state_file, write_state_file, FileState, State},
+    sync::{
+        check_site, hash_file, into_batches, mtime, SyncError, DEFAULT_BATCH_FILES,
+        DEFAULT_BATCH_SIZE,
+    },
+};
+
+fn join(base: &str, name: &str) -> String {
+    if base.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", base, name)
+    }
+}
+
+/// Pairs of remote path and local file to upload for `local`. A file goes to `remote`,
+/// or into `remote` if it ends with a `/`. A directory is uploaded with everything in
+/// it, into `remote`. Without `remote`, both keep their name and go to the root of the
+/// site. `skip` is never uploaded, so that a state file inside `local` stays local.
+pub fn plan_upload(
+    local: &Path,
+    remote: Option<&str>,
+    skip: Option<&Path>,
+) -> io::Result<Vec<(String, PathBuf)>> {
+    let name = std::path::absolute(local)?
+        .file_name()
+        .and_then(|it| it.to_str())
+        .unwrap_or_default()
+        .to_string();
+    if !std::fs::metadata(local)?.is_dir() {
+        let remote = match remote {
+            Some(remote) if remote.is_empty() || remote.ends_with('/') => {
+                join(remote.trim_matches('/'), &name)
+            }
+            Some(remote) => remote.trim_matches('/').to_string(),
+            None => name,
+        };
+        return Ok(vec![(remote, local.to_path_buf())]);
+    }
+    let base = remote.map_or(name, |it| it.trim_matches('/').to_string());
+    let skip = skip.map(std::path::absolute).transpose()?;
+    let mut files = vec![];
+    for entry in WalkDir::new(local).sort_by_file_name() {
+        let entry = entry?;
+        if !entry.file_type().is_file()
+            || skip.as_ref().is_some_and(|skip| {
+                std::path::absolute(entry.path()).is_ok_and(|it| &it == skip)
+            })
+        {
+            continue;
+        }
+        let subpath = entry.path().strip_prefix(local).unwrap().to_str().unwrap();
+        files.push((join(&base, subpath), entry.into_path()));
+    }
+    Ok(files)
+}
+
+/// Key of the remote path `name` in `state`, whose paths are relative to its remote
+/// directory. `None` if `name` is outside of it.
+fn state_key(state: &State, name: &str) -> Option<String> {
+    match &state.remote_dir {
+        Some(remote_dir) => {
+            Some(name.strip_prefix(remote_dir)?.strip_prefix('/')?.to_string())
+        }
+        None => Some(name.to_string()),
+    }
+}
+
+/// Read the state file at `state_path`, if one is given and it exists.
+async fn read_existing_state(
+    state_path: Option<&Path>,
+    username: Option<&str>,
+) -> Result<Option<State>, SyncError> {
+    let Some(state_path) = state_path else {
+        return Ok(None);
+    };
+    match read_state_file(state_path).await {
+        Ok(state) => {
+            check_site(&state, username)?;
+            Ok(Some(state))
+        }
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(SyncError::StateError(error)),
+    }
+}
+
+/// Upload `files`, pairs of remote path and local file, and record them in the state
+/// file at `state_path` if it exists. Returns the uploaded remote paths.
+pub async fn upload(
+    neocities: &Neocities,
+    files: Vec<(String, PathBuf)>,
+    state_path: Option<&Path>,
+    username: Option<&str>,
+) -> Result<Vec<String>, SyncError> {
+    let mut state = read_existing_state(state_path, username).await?;
+    let mut planned = vec![];
+    for (name, path) in files {
+        let metadata = fs::metadata(&path).await?;
+        planned.push((name, path, metadata));
+    }
+    let mut uploaded = vec![];
+    for batch in into_batches(
+        planned,
+        |(_, _, metadata)| metadata.len(),
+        DEFAULT_BATCH_SIZE,
+        DEFAULT_BATCH_FILES,
+    ) {
+        let mut file_states = vec![];
+        if let Some(state) = &state {
+            for (name, path, metadata) in &batch {
+                // Files outside the directory the state covers are not recorded.
+                let Some(key) = state_key(state, name) else {
+                    continue;
+                };
+                let (hash, size) = hash_file(path).await?;
+                file_states.push((
+                    key,
+                    FileState {
+                        hash,
+                        size: Some(size),
+                        mtime: mtime(metadata),
+                        uploaded_at: Some(now()),
+                    },
+                ));
+            }
+        }
+        neocities
+            .upload(batch.iter().map(|(name, path, _)| (name.clone(), path.clone())))
+            .await?;
+        uploaded.extend(batch.into_iter().map(|(name, ..)| name));
+        // Write the state after every batch, so that an interrupted upload keeps
+        // the files which made it.
+        if let (Some(state), Some(state_path)) = (&mut state, state_path) {
+            state.files.extend(file_states);
+            write_state_file(state, state_path).await?;
+        }
+    }
+    Ok(uploaded)
+}
+
+/// Delete `paths` from the site, and drop them from the state file at `state_path` if
+/// it exists. Deleting a directory deletes everything in it.
+pub async fn remove(
+    neocities: &Neocities,
+    paths: Vec<String>,
+    state_path: Option<&Path>,
+    username: Option<&str>,
+) -> Result<Vec<String>, SyncError> {
+    let state = read_existing_state(state_path, username).await?;
+    let paths =
+        paths.iter().map(|it| it.trim_matches('/').to_string()).collect::<Vec<_>>();
+    neocities.delete(paths.clone()).await?;
+    if let (Some(mut state), Some(state_path)) = (state, state_path) {
+        let is_inside = |name: &str, path: &str| {
+            name == path
+                || name.strip_prefix(path).is_some_and(|it| it.starts_with('/'))
+        };
+        // Deleting the directory the state covers, or one of its parents, deletes
+        // every file in the state.
+        if state.remote_dir.as_ref().is_some_and(|remote_dir| {
+            paths.iter().any(|path| is_inside(remote_dir, path))
+        }) {
+            state.files.clear();
+        }
+        let keys =
+            paths.iter().filter_map(|it| state_key(&state, it)).collect::<Vec<_>>();
+        state.files.retain(|name, _| !keys.iter().any(|key| is_inside(name, key)));
+        write_state_file(&state, state_path).await?;
+    }
+    Ok(paths)
+}
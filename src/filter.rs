@@ -0,0 +1,111 @@
+use std::path::{Path, PathBuf};
+
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+
+pub const IGNORE_FILE_NAME: &str = ".neocitiesignore";
+
+/// Decides which paths are left out of a sync, using `.neocitiesignore` files (with
+/// gitignore semantics) and `--exclude`/`--include` globs, and which remote paths are
+/// protected from deletion by `--keep` globs. Paths are relative to the synced
+/// directory.
+pub struct Filter {
+    root: PathBuf,
+    overrides: Gitignore,
+    includes: Vec<String>,
+    keep: Gitignore,
+    /// Loaded `.neocitiesignore` files, parents before children.
+    ignore_files: Vec<Gitignore>,
+}
+
+impl Filter {
+    /// `includes` take precedence over `excludes`, and both take precedence over
+    /// `.neocitiesignore` files.
+    pub fn new(
+        root: impl AsRef<Path>,
+        excludes: &[String],
+        includes: &[String],
+        keep: &[String],
+    ) -> Result<Self, ignore::Error> {
+        let root = std::path::absolute(root)?;
+        let mut builder = GitignoreBuilder::new(&root);
+        for exclude in excludes {
+            builder.add_line(None, exclude)?;
+        }
+        for include in includes {
+            builder.add_line(None, &format!("!{}", include))?;
+        }
+        let overrides = builder.build()?;
+        let mut builder = GitignoreBuilder::new(&root);
+        for keep in keep {
+            builder.add_line(None, keep)?;
+        }
+        let keep = builder.build()?;
+        Ok(Self {
+            root,
+            overrides,
+            includes: includes.to_vec(),
+            keep,
+            ignore_files: vec![],
+        })
+    }
+
+    /// Load the `.neocitiesignore` file in `directory`, if there is one. Directories
+    /// must be loaded before their subdirectories.
+    pub fn load(&mut self, directory: impl AsRef<Path>) -> Result<(), ignore::Error> {
+        let directory = self.root.join(directory);
+        let path = directory.join(IGNORE_FILE_NAME);
+        if !path.is_file() {
+            return Ok(());
+        }
+        let mut builder = GitignoreBuilder::new(&directory);
+        if let Some(error) = builder.add(path) {
+            return Err(error);
+        }
+        self.ignore_files.push(builder.build()?);
+        Ok(())
+    }
+
+    pub fn is_ignored(&self, path: impl AsRef<Path>, is_dir: bool) -> bool {
+        let path = self.root.join(path);
+        if path.file_name().is_some_and(|it| it == IGNORE_FILE_NAME) {
+            return true;
+        }
+        let matchers =
+            std::iter::once(&self.overrides).chain(self.ignore_files.iter().rev());
+        for matcher in matchers {
+            if !path.starts_with(matcher.path()) {
+                continue;
+            }
+            match matcher.matched_path_or_any_parents(&path, is_dir) {
+                Match::None => continue,
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+            }
+        }
+        false
+    }
+
+    /// Whether an include glob could match a path inside `directory`, so that it has to
+    /// be walked even if it is ignored.
+    pub fn may_include_below(&self, directory: &str) -> bool {
+        let directory = format!("{}/", directory);
+        self.includes.iter().any(|include| {
+            // Like in gitignore, globs without a slash, except at the end, match at any
+            // depth.
+            if !include.trim_end_matches('/').contains('/') {
+                return true;
+            }
+            let include = include.strip_prefix('/').unwrap_or(include);
+            let literal =
+                include.split(['*', '?', '[', '\\']).next().unwrap_or_default();
+            literal.starts_with(&directory) || directory.starts_with(literal)
+        })
+    }
+
+    pub fn is_kept(&self, path: impl AsRef<Path>) -> bool {
+        self.keep.matched_path_or_any_parents(self.root.join(path), false).is_ignore()
+    }
+}
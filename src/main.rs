@@ -1,15 +1,32 @@
 mod data;
+mod files;
+mod filter;
 mod neocities;
+mod pull;
 mod state;
 mod sync;
 
-use std::{env, path::PathBuf, process::exit};
+use std::{
+    env,
+    path::PathBuf,
+    process::exit,
+    time::{Duration, Instant},
+};
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use colored::*;
 use data::Data;
-use neocities::Neocities;
-use sync::{sync, SyncError};
+use files::{plan_upload, remove, upload};
+use neocities::{
+    Neocities, NeocitiesError, DEFAULT_API_URL, DEFAULT_MAX_RETRY_DELAY,
+    DEFAULT_RETRIES,
+};
+use pull::{pull, PullOptions};
+use serde::Serialize;
+use sync::{
+    status, sync, Status, SyncError, SyncOptions, SyncStats, UploadReason,
+    VerifyRemote, DEFAULT_BATCH_FILES, DEFAULT_BATCH_SIZE, DEFAULT_CONCURRENCY,
+};
 
 #[derive(Parser, Debug)]
 #[command(
@@ -24,6 +41,20 @@ Sync files to neocities while doing the least amount of API requests."#
 struct Args {
     #[command(subcommand)]
     command: Commands,
+    /// Base URL of the neocities API.
+    #[arg(long, global = true, env = "NEOCITIES_API_URL", default_value = DEFAULT_API_URL)]
+    api_url: String,
+    /// Number of times a request is retried after a network error, HTTP 429 or 5xx.
+    #[arg(long, global = true, default_value_t = DEFAULT_RETRIES)]
+    retries: u32,
+    /// Maximum delay in seconds between retries.
+    #[arg(
+        long,
+        global = true,
+        default_value_t = DEFAULT_MAX_RETRY_DELAY.as_secs_f64(),
+        value_parser = parse_seconds
+    )]
+    max_retry_delay: f64,
 }
 
 #[derive(Debug, Subcommand)]
@@ -45,10 +76,231 @@ enum Commands {
         /// Path to the state file. Used to keep track of the last sync.
         #[arg(short, long)]
         state: Option<PathBuf>,
-        /// Use this if you are NOT a supporter.
+        /// Directory on neocities to sync to, instead of the root of the site. Only
+        /// files inside it are deleted.
+        #[arg(long, value_name = "DIR")]
+        remote_dir: Option<String>,
+        /// Ignore the state file and fetch the state from neocities.
+        #[arg(long)]
+        refetch_state: bool,
+        /// Use this if you are NOT a supporter. Detected automatically once neocities
+        /// rejects a file because of its type.
+        #[arg(short, long, conflicts_with = "all_file_types")]
+        ignore_disallowed_file_types: bool,
+        /// Upload files of every type, even if neocities rejected some of them before.
+        #[arg(long)]
+        all_file_types: bool,
+        /// Print what would be uploaded and deleted without changing anything.
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// Hash every file, even those whose size and modification time match the
+        /// state file.
+        #[arg(long)]
+        rehash: bool,
+        /// Check whether the site was changed outside of neocities-sync before
+        /// syncing, and what to do if it was.
+        #[arg(
+            long,
+            value_enum,
+            value_name = "MODE",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "refuse"
+        )]
+        verify_remote: Option<VerifyRemote>,
+        /// Leave paths matching this glob out of the sync. Can be repeated.
+        #[arg(short, long, value_name = "GLOB")]
+        exclude: Vec<String>,
+        /// Sync paths matching this glob even if they are excluded or ignored. Can be
+        /// repeated.
+        #[arg(long, value_name = "GLOB")]
+        include: Vec<String>,
+        /// Never delete files from neocities.
+        #[arg(long)]
+        no_delete: bool,
+        /// Never delete remote paths matching this glob. Can be repeated.
+        #[arg(short, long, value_name = "GLOB")]
+        keep: Vec<String>,
+        /// Abort the sync if it would delete more than this many files.
+        #[arg(long, value_name = "N")]
+        max_delete: Option<usize>,
+        /// Maximum total size in bytes of the files sent in a single upload request.
+        #[arg(long, default_value_t = DEFAULT_BATCH_SIZE)]
+        batch_size: u64,
+        /// Maximum number of files sent in a single upload request.
+        #[arg(long, default_value_t = DEFAULT_BATCH_FILES)]
+        batch_files: usize,
+        /// Maximum number of upload requests in flight at once.
+        #[arg(long, default_value_t = DEFAULT_CONCURRENCY)]
+        concurrency: usize,
+        /// Output format of the sync result.
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        output: OutputFormat,
+    },
+    /// Show how the directory, the state file and neocities differ, without changing
+    /// anything.
+    Status {
         #[arg(short, long)]
+        username: Option<String>,
+        /// The directory to compare.
+        #[arg(default_value = ".")]
+        path: PathBuf,
+        /// Path to the state file. Used to keep track of the last sync.
+        #[arg(short, long)]
+        state: Option<PathBuf>,
+        /// Directory on neocities to compare against, instead of the root of the site.
+        #[arg(long, value_name = "DIR")]
+        remote_dir: Option<String>,
+        /// Ignore the state file and compare against neocities only.
+        #[arg(long)]
+        refetch_state: bool,
+        /// Use this if you are NOT a supporter. Detected automatically once neocities
+        /// rejects a file because of its type.
+        #[arg(short, long, conflicts_with = "all_file_types")]
         ignore_disallowed_file_types: bool,
+        /// Upload files of every type, even if neocities rejected some of them before.
+        #[arg(long)]
+        all_file_types: bool,
+        /// Hash every file, even those whose size and modification time match the
+        /// state file.
+        #[arg(long)]
+        rehash: bool,
+        /// Leave paths matching this glob out of the comparison. Can be repeated.
+        #[arg(short, long, value_name = "GLOB")]
+        exclude: Vec<String>,
+        /// Compare paths matching this glob even if they are excluded or ignored. Can
+        /// be repeated.
+        #[arg(long, value_name = "GLOB")]
+        include: Vec<String>,
+        /// Output format of the status.
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        output: OutputFormat,
+    },
+    /// List the files on neocities.
+    Ls {
+        /// The directory to list. Defaults to the root of the site.
+        remote_path: Option<String>,
+        #[arg(short, long)]
+        username: Option<String>,
+        /// Show the size, last update and SHA1 hash of every file.
+        #[arg(short, long)]
+        long: bool,
+        /// List subdirectories too.
+        #[arg(short = 'R', long)]
+        recursive: bool,
+        /// Sort the files by this key.
+        #[arg(long, value_enum, default_value_t = SortKey::Name)]
+        sort: SortKey,
+        /// Reverse the order.
+        #[arg(short, long)]
+        reverse: bool,
+        /// Print the files as JSON.
+        #[arg(long)]
+        json: bool,
     },
+    /// Show statistics of a site.
+    Info {
+        /// The site to show. Defaults to the logged in site. Other sites are queried
+        /// without logging in.
+        sitename: Option<String>,
+        #[arg(short, long)]
+        username: Option<String>,
+        /// Print the statistics as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Download the files of a site into a directory.
+    Pull {
+        #[arg(short, long)]
+        username: Option<String>,
+        /// The directory to download into.
+        #[arg(default_value = ".")]
+        path: PathBuf,
+        /// Path to the state file. Used to keep track of the last sync.
+        #[arg(short, long)]
+        state: Option<PathBuf>,
+        /// URL the site is served from. Defaults to https://USERNAME.neocities.org.
+        #[arg(long, env = "NEOCITIES_SITE_URL", value_parser = parse_site_url)]
+        site_url: Option<String>,
+        /// Maximum number of downloads in flight at once.
+        #[arg(long, default_value_t = DEFAULT_CONCURRENCY)]
+        concurrency: usize,
+    },
+    /// Upload a file or a directory.
+    Upload {
+        /// The file or directory to upload.
+        local: PathBuf,
+        /// Where to upload it on neocities. Defaults to its name, at the root of the
+        /// site.
+        remote: Option<String>,
+        #[arg(short, long)]
+        username: Option<String>,
+        /// Path to a state file to record the upload in, if it exists.
+        #[arg(short, long)]
+        state: Option<PathBuf>,
+    },
+    /// Delete files or directories from neocities.
+    Rm {
+        /// The paths to delete.
+        #[arg(required = true)]
+        remote: Vec<String>,
+        #[arg(short, long)]
+        username: Option<String>,
+        /// Path to a state file to remove the paths from, if it exists.
+        #[arg(short, long)]
+        state: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SortKey {
+    Name,
+    /// Largest first.
+    Size,
+    /// Most recently updated first.
+    Time,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// A number of seconds which fits in a `Duration`, so that it is never negative or NaN.
+fn parse_seconds(value: &str) -> Result<f64, String> {
+    let seconds = value.parse::<f64>().map_err(|error| error.to_string())?;
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| "expected a number of seconds of at least 0".to_string())?;
+    Ok(seconds)
+}
+
+/// A URL which files can be downloaded from, by appending their path to it.
+fn parse_site_url(value: &str) -> Result<String, String> {
+    let url = reqwest::Url::parse(value).map_err(|error| error.to_string())?;
+    if url.cannot_be_a_base() {
+        return Err("expected a URL like https://example.com".to_string());
+    }
+    Ok(value.to_string())
+}
+
+/// `SyncOptions::remote_dir` from `--remote-dir`, where `/` means the root of the site.
+fn remote_dir(remote_dir: Option<String>) -> Option<String> {
+    remote_dir.map(|it| it.trim_matches('/').to_string()).filter(|it| !it.is_empty())
+}
+
+/// `SyncOptions::ignore_disallowed_file_types` from the command line flags.
+fn file_types(
+    ignore_disallowed_file_types: bool,
+    all_file_types: bool,
+) -> Option<bool> {
+    if ignore_disallowed_file_types {
+        Some(true)
+    } else if all_file_types {
+        Some(false)
+    } else {
+        None
+    }
 }
 
 fn get_username(username: Option<String>, data: &Data) -> (String, bool) {
@@ -69,7 +321,40 @@ fn get_username(username: Option<String>, data: &Data) -> (String, bool) {
     (username, is_default)
 }
 
-async fn login_cmd(mut data: Data) {
+/// The API key from `NEOCITIES_API_KEY`, or else the one saved by the login command.
+fn get_api_key(username: Option<String>, data: &Data) -> String {
+    env::var("NEOCITIES_API_KEY").unwrap_or_else(|_| {
+        let (username, _) = get_username(username, data);
+        let entry = keyring::Entry::new("neocities-sync", &username).unwrap();
+        match entry.get_password() {
+            Ok(api_key) => api_key,
+            Err(keyring::Error::NoEntry) => {
+                eprintln!(
+                    "{} Use {} to login first.",
+                    "Not logged in.".bright_red(),
+                    "neocities-sync login".bright_cyan()
+                );
+                exit(1);
+            }
+            Err(err) => panic!("{:#?}", err),
+        }
+    })
+}
+
+/// Name of the site the API key of `neocities` belongs to. `NEOCITIES_API_KEY` takes
+/// precedence over `--username`, so its site is asked from neocities instead.
+async fn get_sitename(
+    neocities: &Neocities,
+    username: Option<String>,
+    data: &Data,
+) -> Result<Option<String>, NeocitiesError> {
+    if env::var("NEOCITIES_API_KEY").is_err() {
+        return Ok(username.or_else(|| data.get_default_username().map(str::to_string)));
+    }
+    Ok(Some(neocities.info(None).await?.sitename))
+}
+
+async fn login_cmd(mut data: Data, mut neocities: Neocities) {
     let Ok(username) = inquire::Text::new("Enter your username:").prompt() else {
         exit(1);
     };
@@ -78,10 +363,20 @@ async fn login_cmd(mut data: Data) {
     else {
         exit(1);
     };
-    let mut neocities = Neocities::new();
-    if !neocities.login(&username, &password).await.unwrap() {
-        eprintln!("{}", "Username or password is incorrect.".bright_red());
-        exit(1);
+    match neocities.login(&username, &password).await {
+        Ok(true) => {}
+        Ok(false) => {
+            eprintln!("{}", "Username or password is incorrect.".bright_red());
+            exit(1);
+        }
+        Err(NeocitiesError::ReqwestError(error)) => {
+            eprintln!("{} {}", "Network error:".bright_red(), error);
+            exit(1);
+        }
+        Err(error) => {
+            eprintln!("{} {}", "Neocities returned an error:".bright_red(), error);
+            exit(1);
+        }
     }
     let entry = keyring::Entry::new("neocities-sync", &username).unwrap();
     entry.set_password(&neocities.api_key.unwrap()).unwrap();
@@ -115,72 +410,570 @@ async fn logout_cmd(mut data: Data, username: Option<String>) {
     }
 }
 
+/// Print a diagnostic for a failed command and exit.
+fn report_error(error: SyncError) -> ! {
+    match error {
+        SyncError::NeocitiesError(NeocitiesError::InvalidAuth(_)) => {
+            eprintln!(
+                "{} Use {} to login again.",
+                "Invalid session.".bright_red(),
+                "neocities-sync login".bright_cyan()
+            );
+            exit(1);
+        }
+        SyncError::NeocitiesError(NeocitiesError::InvalidFileType(error)) => {
+            if let Some(message) = error.message {
+                eprintln!("{}", message);
+            }
+            eprintln!(
+                "{} Use {} to ignore such files.",
+                "Invalid file type.".bright_red(),
+                "--ignore-disallowed-file-types".bright_cyan()
+            );
+            exit(1);
+        }
+        SyncError::NeocitiesError(NeocitiesError::MissingFiles(_)) => {
+            eprintln!(
+                "{} Re-run the sync command with {}.",
+                "Out of sync.".bright_red(),
+                "--refetch-state".bright_cyan()
+            );
+            exit(1);
+        }
+        SyncError::NeocitiesError(NeocitiesError::ReqwestError(error)) => {
+            eprintln!("{} {}", "Network error:".bright_red(), error);
+            exit(1);
+        }
+        SyncError::NeocitiesError(NeocitiesError::IOError(error)) => {
+            eprintln!("{} {}", "File error:".bright_red(), error);
+            exit(1);
+        }
+        SyncError::NeocitiesError(error) => {
+            eprintln!("{} {}", "Neocities returned an error:".bright_red(), error);
+            exit(1);
+        }
+        SyncError::TooManyDeletions { planned, max } => {
+            eprintln!(
+                "{} The sync would delete {} files, but at most {} are allowed. Use {} \
+                 to keep remote files, or raise {}.",
+                "Refusing to delete.".bright_red(),
+                planned,
+                max,
+                "--no-delete".bright_cyan(),
+                "--max-delete".bright_cyan()
+            );
+            exit(1);
+        }
+        SyncError::IgnoreError(error) => {
+            eprintln!("{} {}", "Invalid ignore pattern:".bright_red(), error);
+            exit(1);
+        }
+        SyncError::StateError(error) => {
+            eprintln!("{} {}", "Invalid state file:".bright_red(), error);
+            eprintln!("Delete it to fetch the state from the site again.");
+            exit(1);
+        }
+        SyncError::StateMismatch { expected, actual } => {
+            eprintln!(
+                "{} It was written for {}, not {}. Use {} to fetch the state \
+                 of {}.",
+                "State file belongs to another site.".bright_red(),
+                expected,
+                actual,
+                "--refetch-state".bright_cyan(),
+                actual
+            );
+            exit(1);
+        }
+        SyncError::RemoteDiverged { paths } => {
+            for path in &paths {
+                eprintln!("[diverged] {}", path);
+            }
+            eprintln!(
+                "{} Use {} to sync anyway, or {} to sync against the files on \
+                 neocities.",
+                "The site was changed outside of neocities-sync.".bright_red(),
+                "--verify-remote=warn".bright_cyan(),
+                "--verify-remote=reconcile".bright_cyan()
+            );
+            exit(1);
+        }
+        SyncError::HashMismatch { .. } => {
+            eprintln!(
+                "{} {}",
+                "Download does not match neocities:".bright_red(),
+                error
+            );
+            eprintln!("The site may still be cached. Try again later.");
+            exit(1);
+        }
+        SyncError::IOError(error) => {
+            eprintln!("{} {}", "File error:".bright_red(), error);
+            exit(1);
+        }
+    }
+}
+
 async fn sync_cmd(
     data: Data,
+    mut neocities: Neocities,
     username: Option<String>,
     path: PathBuf,
     state: Option<PathBuf>,
-    ignore_disallowed_file_types: bool,
+    mut options: SyncOptions,
+    output: OutputFormat,
 ) {
-    let api_key = env::var("NEOCITIES_API_KEY").unwrap_or_else(|_| {
-        let (username, _) = get_username(username, &data);
-        let entry = keyring::Entry::new("neocities-sync", &username).unwrap();
-        match entry.get_password() {
-            Ok(api_key) => api_key,
-            Err(keyring::Error::NoEntry) => {
-                eprintln!(
-                    "{} Use {} to login first.",
-                    "Not logged in.".bright_red(),
-                    "neocities-sync login".bright_cyan()
-                );
-                exit(1);
-            }
-            Err(err) => panic!("{:#?}", err),
+    neocities.api_key = Some(get_api_key(username.clone(), &data));
+    let state = state.unwrap_or_else(|| path.join(".state"));
+    let started = Instant::now();
+    let result = match get_sitename(&neocities, username, &data).await {
+        Ok(sitename) => {
+            options.username = sitename;
+            sync(&neocities, path, state, &options).await
         }
+        Err(error) => Err(error.into()),
+    };
+    if output == OutputFormat::Json {
+        print_report(&neocities, &options, started, &result);
+        if result.is_err() || result.is_ok_and(|it| !it.rejected.is_empty()) {
+            exit(1);
+        }
+        return;
+    }
+    let stats = match result {
+        Ok(stats) => stats,
+        Err(error) => report_error(error),
+    };
+    for path in &stats.ignored {
+        eprintln!("[ignored] {}", path);
+    }
+    for path in &stats.kept {
+        eprintln!("[kept] {}", path);
+    }
+    for path in &stats.diverged {
+        eprintln!("{} {}", "[diverged]".bright_yellow(), path);
+    }
+    for path in &stats.reconciled {
+        eprintln!("{} {}", "[reconciled]".bright_yellow(), path);
+    }
+    if options.dry_run {
+        print_plan(&stats);
+        return;
+    }
+    eprintln!(
+        "{} {}, {} {}, {} {}",
+        "uploaded".bright_green(),
+        stats.uploaded.len(),
+        "deleted".bright_red(),
+        stats.deleted.len(),
+        "renamed".bright_blue(),
+        stats.renamed.len()
+    );
+    if !stats.rejected.is_empty() {
+        for path in &stats.rejected {
+            eprintln!("{} {}", "[rejected]".bright_red(), path);
+        }
+        eprintln!(
+            "{} Neocities rejected {} files. Only supporters may upload them, so they \
+             are left out of the next syncs. Use {} to upload them anyway.",
+            "Invalid file type.".bright_red(),
+            stats.rejected.len(),
+            "--all-file-types".bright_cyan()
+        );
+        exit(1);
+    }
+}
+
+async fn status_cmd(
+    data: Data,
+    mut neocities: Neocities,
+    username: Option<String>,
+    path: PathBuf,
+    state: Option<PathBuf>,
+    mut options: SyncOptions,
+    output: OutputFormat,
+) {
+    neocities.api_key = Some(get_api_key(username.clone(), &data));
+    options.username = match get_sitename(&neocities, username, &data).await {
+        Ok(sitename) => sitename,
+        Err(error) => report_error(error.into()),
+    };
+    let state = state.unwrap_or_else(|| path.join(".state"));
+    let status = match status(&neocities, path, state, &options).await {
+        Ok(status) => status,
+        Err(error) => report_error(error),
+    };
+    if output == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&status).unwrap());
+        return;
+    }
+    for path in &status.ignored {
+        eprintln!("[ignored] {}", path);
+    }
+    print_status(&status);
+}
+
+struct LsOptions {
+    long: bool,
+    recursive: bool,
+    sort: SortKey,
+    reverse: bool,
+    json: bool,
+}
+
+/// Parse a date like `Sat, 13 Feb 2016 03:04:00 -0000` into seconds since the Unix
+/// epoch, for sorting.
+fn parse_date(date: &str) -> Option<i64> {
+    const MONTHS: &[&str] = &[
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov",
+        "Dec",
+    ];
+    let date = date.split_once(", ").map_or(date, |(_, it)| it);
+    let [day, month, year, time, offset] =
+        date.split_whitespace().collect::<Vec<_>>().try_into().ok()?;
+    let day: i64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|it| *it == month)? as i64 + 1;
+    let year: i64 = year.parse().ok()?;
+    let mut time = time.split(':').map(|it| it.parse::<i64>());
+    let seconds = time.try_fold(0, |acc, it| it.map(|it| acc * 60 + it)).ok()?;
+    let offset: i64 = offset.parse().ok()?;
+    let offset = (offset / 100 * 60 + offset % 100) * 60;
+    // Days since the epoch, from http://howardhinnant.github.io/date_algorithms.html
+    let (year, month) =
+        if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era =
+        year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    Some(days * 86400 + seconds - offset)
+}
+
+async fn ls_cmd(
+    data: Data,
+    mut neocities: Neocities,
+    remote_path: Option<String>,
+    username: Option<String>,
+    options: LsOptions,
+) {
+    neocities.api_key = Some(get_api_key(username, &data));
+    let prefix = remote_path.as_deref().map(|it| it.trim_matches('/')).unwrap_or("");
+    let mut entries = match neocities.list(remote_path.as_deref()).await {
+        Ok(entries) => entries,
+        Err(error) => report_error(error.into()),
+    };
+    if !options.recursive {
+        entries.retain(|entry| {
+            let name = match prefix {
+                "" => Some(entry.path.as_str()),
+                prefix => {
+                    entry.path.strip_prefix(prefix).and_then(|it| it.strip_prefix('/'))
+                }
+            };
+            name.is_some_and(|it| !it.contains('/'))
+        });
+    }
+    entries.sort_by(|a, b| match options.sort {
+        SortKey::Name => a.path.cmp(&b.path),
+        SortKey::Size => b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)),
+        SortKey::Time => parse_date(&b.updated_at)
+            .cmp(&parse_date(&a.updated_at))
+            .then_with(|| a.path.cmp(&b.path)),
     });
+    if options.reverse {
+        entries.reverse();
+    }
+    if options.json {
+        println!("{}", serde_json::to_string_pretty(&entries).unwrap());
+        return;
+    }
+    for entry in &entries {
+        let path = if entry.is_directory {
+            format!("{}/", entry.path).bright_blue().to_string()
+        } else {
+            entry.path.clone()
+        };
+        if !options.long {
+            println!("{}", path);
+            continue;
+        }
+        let size = entry.size.map_or_else(|| "-".to_string(), format_size);
+        let hash = entry.sha1_hash.as_deref().unwrap_or("-");
+        println!("{:>10}  {}  {:<40}  {}", size, entry.updated_at, hash, path);
+    }
+}
+
+async fn info_cmd(
+    data: Data,
+    mut neocities: Neocities,
+    sitename: Option<String>,
+    username: Option<String>,
+    json: bool,
+) {
+    if sitename.is_none() {
+        neocities.api_key = Some(get_api_key(username, &data));
+    }
+    let info = match neocities.info(sitename.as_deref()).await {
+        Ok(info) => info,
+        Err(NeocitiesError::SiteNotFound(_)) => {
+            eprintln!("{}", "Site not found.".bright_red());
+            exit(1);
+        }
+        Err(error) => report_error(error.into()),
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&info).unwrap());
+        return;
+    }
+    let none = || "-".dimmed().to_string();
+    let rows = [
+        ("sitename", info.sitename),
+        ("hits", info.hits.to_string()),
+        ("views", info.views.to_string()),
+        ("created", info.created_at),
+        ("updated", info.last_updated.unwrap_or_else(none)),
+        ("domain", info.domain.unwrap_or_else(none)),
+        ("tags", if info.tags.is_empty() { none() } else { info.tags.join(", ") }),
+    ];
+    for (name, value) in rows {
+        println!("{:<10} {}", name.bright_cyan(), value);
+    }
+}
+
+async fn pull_cmd(
+    data: Data,
+    mut neocities: Neocities,
+    username: Option<String>,
+    path: PathBuf,
+    state: Option<PathBuf>,
+    site_url: Option<String>,
+    mut options: PullOptions,
+) {
+    neocities.api_key = Some(get_api_key(username.clone(), &data));
+    options.username = match get_sitename(&neocities, username, &data).await {
+        Ok(sitename) => sitename,
+        Err(error) => report_error(error.into()),
+    };
+    let Some(site_url) = site_url.or_else(|| {
+        options.username.as_ref().map(|it| format!("https://{}.neocities.org", it))
+    }) else {
+        eprintln!(
+            "{} Use {} or {}.",
+            "Unknown site.".bright_red(),
+            "--username".bright_cyan(),
+            "--site-url".bright_cyan()
+        );
+        exit(1);
+    };
     let state = state.unwrap_or_else(|| path.join(".state"));
-    let mut neocities = Neocities::new();
-    neocities.api_key = Some(api_key);
-    let stats = match sync(&neocities, path, state, ignore_disallowed_file_types).await
-    {
+    let stats = match pull(&neocities, &site_url, path, state, &options).await {
         Ok(stats) => stats,
-        Err(error) => match error {
-            SyncError::InvalidAuth => {
-                eprintln!(
-                    "{} Use {} to login again.",
-                    "Invalid session.".bright_red(),
-                    "neocities-sync login".bright_cyan()
-                );
-                exit(1);
-            }
-            SyncError::InvalidFileType => {
-                eprintln!(
-                    "{} Use {} to ignore such files.",
-                    "Invalid file type.".bright_red(),
-                    "--ignore-disallowed-file-types".bright_cyan()
-                );
-                exit(1);
-            }
-            SyncError::MissingFiles => {
+        Err(error) => report_error(error),
+    };
+    for path in &stats.downloaded {
+        println!("{} {}", "[downloaded]".bright_green(), path);
+    }
+    eprintln!(
+        "{} {}, {} {}",
+        "downloaded".bright_green(),
+        stats.downloaded.len(),
+        "unchanged".dimmed(),
+        stats.unchanged.len()
+    );
+}
+
+async fn upload_cmd(
+    data: Data,
+    mut neocities: Neocities,
+    local: PathBuf,
+    remote: Option<String>,
+    username: Option<String>,
+    state: Option<PathBuf>,
+) {
+    let files = match plan_upload(&local, remote.as_deref(), state.as_deref()) {
+        Ok(files) => files,
+        Err(error) => {
+            eprintln!("{} {}: {}", "Cannot read".bright_red(), local.display(), error);
+            exit(1);
+        }
+    };
+    neocities.api_key = Some(get_api_key(username.clone(), &data));
+    let site = match get_sitename(&neocities, username, &data).await {
+        Ok(sitename) => sitename,
+        Err(error) => report_error(error.into()),
+    };
+    let uploaded =
+        match upload(&neocities, files, state.as_deref(), site.as_deref()).await {
+            Ok(uploaded) => uploaded,
+            Err(error) => report_error(error),
+        };
+    for path in &uploaded {
+        println!("{} {}", "[uploaded]".bright_green(), path);
+    }
+    eprintln!("{} {}", "uploaded".bright_green(), uploaded.len());
+}
+
+async fn rm_cmd(
+    data: Data,
+    mut neocities: Neocities,
+    remote: Vec<String>,
+    username: Option<String>,
+    state: Option<PathBuf>,
+) {
+    if remote.iter().any(|it| it.trim_matches('/') == "index.html") {
+        eprintln!("{}", "Neocities does not allow deleting index.html.".bright_red());
+        exit(1);
+    }
+    neocities.api_key = Some(get_api_key(username.clone(), &data));
+    let site = match get_sitename(&neocities, username, &data).await {
+        Ok(sitename) => sitename,
+        Err(error) => report_error(error.into()),
+    };
+    let deleted =
+        match remove(&neocities, remote, state.as_deref(), site.as_deref()).await {
+            Ok(deleted) => deleted,
+            Err(SyncError::NeocitiesError(NeocitiesError::MissingFiles(error))) => {
                 eprintln!(
-                    "{} Re-run the sync command after deleting your state file.",
-                    "Out of sync.".bright_red(),
+                    "{} {}",
+                    "File not found:".bright_red(),
+                    error.message.unwrap_or_default()
                 );
                 exit(1);
             }
-            SyncError::ReqwestError(error) => panic!("{:#?}", error),
-            SyncError::IOError(error) => panic!("{:#?}", error),
-        },
-    };
+            Err(error) => report_error(error),
+        };
+    for path in &deleted {
+        println!("{} {}", "[deleted]".bright_red(), path);
+    }
+    eprintln!("{} {}", "deleted".bright_red(), deleted.len());
+}
+
+fn print_status(status: &Status) {
+    let sections = [
+        (&status.new, "new", "[new]".bright_green()),
+        (&status.modified, "modified", "[modified]".bright_yellow()),
+        (&status.deleted, "deleted", "[deleted]".bright_red()),
+        (
+            &status.changed_remotely,
+            "changed remotely",
+            "[changed remotely]".bright_magenta(),
+        ),
+        (
+            &status.missing_remotely,
+            "missing remotely",
+            "[missing remotely]".bright_red(),
+        ),
+        (&status.remote_only, "remote only", "[remote only]".bright_cyan()),
+    ];
+    let mut summary = vec![];
+    for (paths, name, label) in sections {
+        for path in paths {
+            println!("{} {}", label, path);
+        }
+        if !paths.is_empty() {
+            summary.push(format!("{} {}", paths.len(), name));
+        }
+    }
+    if summary.is_empty() {
+        eprintln!("{}", "Up to date.".bright_green());
+    } else {
+        eprintln!("{}", summary.join(", "));
+    }
+}
+
+fn format_size(size: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", size, UNITS[unit])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+fn print_plan(stats: &SyncStats) {
+    for upload in &stats.uploaded {
+        let reason = match upload.reason {
+            UploadReason::New => "[new]".bright_green(),
+            UploadReason::Modified => "[modified]".bright_yellow(),
+        };
+        println!("{} {} ({})", reason, upload.path, format_size(upload.size));
+    }
+    for rename in &stats.renamed {
+        println!("{} {} -> {}", "[rename]".bright_blue(), rename.from, rename.to);
+    }
+    for path in &stats.deleted {
+        println!("{} {}", "[delete]".bright_red(), path);
+    }
     eprintln!(
-        "{} {}, {} {}",
-        "uploaded".bright_green(),
-        stats.uploaded,
-        "deleted".bright_red(),
-        stats.deleted
+        "{} {}, {} {}, {} {} {}",
+        "would upload".bright_green(),
+        stats.uploaded.len(),
+        "would delete".bright_red(),
+        stats.deleted.len(),
+        "would rename".bright_blue(),
+        stats.renamed.len(),
+        "(dry run)".dimmed()
     );
 }
 
+#[derive(Serialize)]
+struct ReportError {
+    error_type: Option<String>,
+    status: Option<u16>,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    dry_run: bool,
+    #[serde(flatten)]
+    stats: &'a SyncStats,
+    duration_ms: u128,
+    requests: usize,
+    errors: Vec<ReportError>,
+}
+
+/// Print the JSON report of a sync. A failed sync does not say what it got done before
+/// the error, so its report has empty file lists.
+fn print_report(
+    neocities: &Neocities,
+    options: &SyncOptions,
+    started: Instant,
+    result: &Result<SyncStats, SyncError>,
+) {
+    let empty = SyncStats::default();
+    let (stats, errors) = match result {
+        Ok(stats) => (stats, vec![]),
+        Err(error) => {
+            let api_error = match error {
+                SyncError::NeocitiesError(error) => error.api_error(),
+                _ => None,
+            };
+            let error = ReportError {
+                error_type: api_error.map(|it| it.error_type.clone()),
+                status: api_error.map(|it| it.status.as_u16()),
+                message: error.to_string(),
+            };
+            (&empty, vec![error])
+        }
+    };
+    let report = Report {
+        dry_run: options.dry_run,
+        stats,
+        duration_ms: started.elapsed().as_millis(),
+        requests: neocities.requests(),
+        errors,
+    };
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     std::panic::set_hook(Box::new(|info| {
@@ -194,11 +987,107 @@ async fn main() -> anyhow::Result<()> {
     }));
     let args = Args::parse();
     let data = Data::new();
+    let mut neocities = Neocities::new();
+    neocities.api_url = args.api_url;
+    neocities.retries = args.retries;
+    neocities.max_retry_delay = Duration::from_secs_f64(args.max_retry_delay);
     match args.command {
-        Commands::Login => login_cmd(data).await,
+        Commands::Login => login_cmd(data, neocities).await,
         Commands::Logout { username } => logout_cmd(data, username).await,
-        Commands::Sync { username, path, state, ignore_disallowed_file_types } => {
-            sync_cmd(data, username, path, state, ignore_disallowed_file_types).await
+        Commands::Sync {
+            username,
+            path,
+            state,
+            remote_dir: dir,
+            refetch_state,
+            ignore_disallowed_file_types,
+            all_file_types,
+            dry_run,
+            rehash,
+            verify_remote,
+            exclude,
+            include,
+            no_delete,
+            keep,
+            max_delete,
+            batch_size,
+            batch_files,
+            concurrency,
+            output,
+        } => {
+            let options = SyncOptions {
+                username: None,
+                remote_dir: remote_dir(dir),
+                refetch_state,
+                ignore_disallowed_file_types: file_types(
+                    ignore_disallowed_file_types,
+                    all_file_types,
+                ),
+                dry_run,
+                rehash,
+                verify_remote,
+                exclude,
+                include,
+                no_delete,
+                keep,
+                max_delete,
+                batch_size,
+                batch_files,
+                concurrency,
+            };
+            sync_cmd(data, neocities, username, path, state, options, output).await
+        }
+        Commands::Status {
+            username,
+            path,
+            state,
+            remote_dir: dir,
+            refetch_state,
+            ignore_disallowed_file_types,
+            all_file_types,
+            rehash,
+            exclude,
+            include,
+            output,
+        } => {
+            let options = SyncOptions {
+                remote_dir: remote_dir(dir),
+                refetch_state,
+                ignore_disallowed_file_types: file_types(
+                    ignore_disallowed_file_types,
+                    all_file_types,
+                ),
+                rehash,
+                exclude,
+                include,
+                ..Default::default()
+            };
+            status_cmd(data, neocities, username, path, state, options, output).await
+        }
+        Commands::Ls {
+            remote_path,
+            username,
+            long,
+            recursive,
+            sort,
+            reverse,
+            json,
+        } => {
+            let options = LsOptions { long, recursive, sort, reverse, json };
+            ls_cmd(data, neocities, remote_path, username, options).await
+        }
+        Commands::Info { sitename, username, json } => {
+            info_cmd(data, neocities, sitename, username, json).await
+        }
+        Commands::Pull { username, path, state, site_url, concurrency } => {
+            let options = PullOptions { concurrency, ..Default::default() };
+            pull_cmd(data, neocities, username, path, state, site_url, options).await
+        }
+        Commands::Upload { local, remote, username, state } => {
+            upload_cmd(data, neocities, local, remote, username, state).await
+        }
+        Commands::Rm { remote, username, state } => {
+            rm_cmd(data, neocities, remote, username, state).await
         }
     }
     Ok(())
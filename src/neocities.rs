@@ -1,6 +1,17 @@
+use std::{
+    fmt,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
 use base64::prelude::*;
-use reqwest::Client;
-use serde::Deserialize;
+use reqwest::{Body, Client, Method, RequestBuilder, Response, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tokio::{fs::File, io::AsyncWriteExt};
+use tokio_util::io::ReaderStream;
 
 #[derive(Debug, Deserialize)]
 struct KeyResponse {
@@ -22,191 +33,447 @@ struct ListResponse {
     // result: String,
     error_type: Option<String>,
     message: Option<String>,
-    files: Option<Vec<Entry>>,
+    #[serde(default)]
+    files: Vec<Entry>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Entry {
-    // pub is_directory: bool,
     pub path: String,
-    // pub updated_at: String,
-    // pub size: Option<u64>,
+    #[serde(default)]
+    pub is_directory: bool,
+    #[serde(default)]
+    pub updated_at: String,
+    /// `None` for directories.
+    pub size: Option<u64>,
+    /// `None` for directories.
     pub sha1_hash: Option<String>,
 }
 
+#[derive(Debug, Deserialize)]
+struct InfoResponse {
+    // result: String,
+    error_type: Option<String>,
+    message: Option<String>,
+    info: Option<SiteInfo>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SiteInfo {
+    pub sitename: String,
+    pub hits: u64,
+    pub views: u64,
+    pub created_at: String,
+    pub last_updated: Option<String>,
+    pub domain: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// An error response returned by the neocities API.
 #[derive(Debug)]
-pub enum UploadError {
-    InvalidFileType,
-    InvalidAuth,
-    ReqwestError(reqwest::Error),
+pub struct ApiError {
+    pub status: StatusCode,
+    pub error_type: String,
+    pub message: Option<String>,
 }
 
-impl From<reqwest::Error> for UploadError {
-    fn from(error: reqwest::Error) -> Self {
-        Self::ReqwestError(error)
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.error_type, self.status)?;
+        if let Some(message) = &self.message {
+            write!(f, ": {}", message)?;
+        }
+        Ok(())
     }
 }
 
 #[derive(Debug)]
-pub enum DeleteError {
-    MissingFiles,
-    InvalidAuth,
+pub enum NeocitiesError {
+    InvalidAuth(ApiError),
+    InvalidFileType(ApiError),
+    MissingFiles(ApiError),
+    TooLarge(ApiError),
+    TooManyFiles(ApiError),
+    FileExists(ApiError),
+    DirectoryExists(ApiError),
+    SiteNotFound(ApiError),
+    /// An `error_type` this client does not know about.
+    Other(ApiError),
     ReqwestError(reqwest::Error),
+    IOError(std::io::Error),
 }
 
-impl From<reqwest::Error> for DeleteError {
-    fn from(error: reqwest::Error) -> Self {
-        Self::ReqwestError(error)
+impl NeocitiesError {
+    fn new(status: StatusCode, error_type: String, message: Option<String>) -> Self {
+        let constructor = match error_type.as_str() {
+            "invalid_auth" => Self::InvalidAuth,
+            "invalid_file_type" => Self::InvalidFileType,
+            "missing_files" => Self::MissingFiles,
+            "too_large" => Self::TooLarge,
+            "too_many_files" => Self::TooManyFiles,
+            "file_exists" => Self::FileExists,
+            "directory_exists" => Self::DirectoryExists,
+            "site_not_found" => Self::SiteNotFound,
+            _ => Self::Other,
+        };
+        constructor(ApiError { status, error_type, message })
+    }
+
+    pub fn api_error(&self) -> Option<&ApiError> {
+        match self {
+            Self::InvalidAuth(error)
+            | Self::InvalidFileType(error)
+            | Self::MissingFiles(error)
+            | Self::TooLarge(error)
+            | Self::TooManyFiles(error)
+            | Self::FileExists(error)
+            | Self::DirectoryExists(error)
+            | Self::SiteNotFound(error)
+            | Self::Other(error) => Some(error),
+            Self::ReqwestError(_) | Self::IOError(_) => None,
+        }
     }
 }
 
-#[derive(Debug)]
-pub enum ListError {
-    InvalidAuth,
-    ReqwestError(reqwest::Error),
+impl fmt::Display for NeocitiesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ReqwestError(error) => error.fmt(f),
+            Self::IOError(error) => error.fmt(f),
+            _ => self.api_error().unwrap().fmt(f),
+        }
+    }
 }
 
-impl From<reqwest::Error> for ListError {
+impl std::error::Error for NeocitiesError {}
+
+impl From<reqwest::Error> for NeocitiesError {
     fn from(error: reqwest::Error) -> Self {
         Self::ReqwestError(error)
     }
 }
 
-#[derive(Debug, Default)]
+impl From<std::io::Error> for NeocitiesError {
+    fn from(error: std::io::Error) -> Self {
+        Self::IOError(error)
+    }
+}
+
+fn check(
+    status: StatusCode,
+    error_type: Option<String>,
+    message: Option<String>,
+) -> Result<(), NeocitiesError> {
+    match error_type {
+        Some(error_type) => Err(NeocitiesError::new(status, error_type, message)),
+        None => Ok(()),
+    }
+}
+
+pub const DEFAULT_API_URL: &str = "https://neocities.org";
+
+pub const DEFAULT_RETRIES: u32 = 4;
+pub const DEFAULT_MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+fn is_transient_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+fn is_transient_error(error: &reqwest::Error) -> bool {
+    error.is_timeout() || error.is_connect() || error.is_request()
+}
+
+/// Parse the `Retry-After` header, which neocities sends as a number of seconds.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let retry_after = response.headers().get("Retry-After")?.to_str().ok()?;
+    retry_after.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// Parse a JSON response body. Error pages which are not JSON, like those of a proxy in
+/// front of the API, are turned into an `http_error`.
+async fn parse<T: DeserializeOwned>(
+    response: Response,
+) -> Result<(StatusCode, T), NeocitiesError> {
+    let status = response.status();
+    match response.json::<T>().await {
+        Ok(body) => Ok((status, body)),
+        Err(error) if error.is_decode() && !status.is_success() => {
+            Err(NeocitiesError::new(status, "http_error".to_string(), None))
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+#[derive(Debug)]
 pub struct Neocities {
     client: Client,
+    requests: AtomicUsize,
+    pub api_url: String,
     pub api_key: Option<String>,
+    /// Number of times a request is retried after a transient failure.
+    pub retries: u32,
+    /// Upper bound of the delay between retries.
+    pub max_retry_delay: Duration,
+}
+
+impl Default for Neocities {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Neocities {
     pub fn new() -> Self {
-        Self { client: Client::new(), api_key: None }
+        Self {
+            client: Client::new(),
+            requests: AtomicUsize::new(0),
+            api_url: DEFAULT_API_URL.to_string(),
+            api_key: None,
+            retries: DEFAULT_RETRIES,
+            max_retry_delay: DEFAULT_MAX_RETRY_DELAY,
+        }
+    }
+
+    /// Number of API requests made so far.
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    fn request(&self, method: Method, name: &str) -> RequestBuilder {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let url = format!("{}/api/{}", self.api_url.trim_end_matches('/'), name);
+        self.client.request(method, url)
+    }
+
+    fn authorization(&self) -> String {
+        format!("Bearer {}", self.api_key.as_ref().unwrap())
+    }
+
+    /// Exponential backoff with jitter, for the given retry attempt.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = RETRY_BASE_DELAY.saturating_mul(1 << attempt.min(16));
+        delay.min(self.max_retry_delay).mul_f64(0.5 + fastrand::f64() / 2.0)
+    }
+
+    /// Send the request built by `build`, retrying timeouts, connection failures, HTTP
+    /// 429 and 5xx responses. `build` is called again for every attempt.
+    async fn send<F, Fut>(&self, build: F) -> Result<Response, NeocitiesError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<RequestBuilder, NeocitiesError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let result = build().await?.send().await;
+            let is_transient = match &result {
+                Ok(response) => is_transient_status(response.status()),
+                Err(error) => is_transient_error(error),
+            };
+            if !is_transient || attempt >= self.retries {
+                return Ok(result?);
+            }
+            let delay = match &result {
+                Ok(response) => retry_after(response),
+                Err(_) => None,
+            };
+            let delay = delay.unwrap_or_else(|| self.backoff(attempt));
+            tokio::time::sleep(delay.min(self.max_retry_delay)).await;
+            attempt += 1;
+        }
     }
 
     pub async fn login(
         &mut self,
         username: &str,
         password: &str,
-    ) -> Result<bool, reqwest::Error> {
+    ) -> Result<bool, NeocitiesError> {
+        let credentials = BASE64_STANDARD.encode(format!("{}:{}", username, password));
         let response = self
-            .client
-            .get("https://neocities.org/api/key")
-            .header(
-                "Authorization",
-                format!(
-                    "Basic {}",
-                    BASE64_STANDARD.encode(format!("{}:{}", username, password))
-                ),
-            )
-            .send()
-            .await?
-            .json::<KeyResponse>()
+            .send(|| async {
+                Ok(self
+                    .request(Method::GET, "key")
+                    .header("Authorization", format!("Basic {}", credentials)))
+            })
             .await?;
-        match response.error_type.as_deref() {
-            Some("invalid_auth") => {
+        let (status, response) = parse::<KeyResponse>(response).await?;
+        match check(status, response.error_type, response.message) {
+            Err(NeocitiesError::InvalidAuth(_)) => {
                 self.api_key = None;
                 Ok(false)
             }
-            Some(error) => {
-                unimplemented!("(login) {} : {}", error, response.message.unwrap())
-            }
-            None => {
+            Err(error) => Err(error),
+            Ok(()) => {
                 self.api_key = response.api_key;
                 Ok(true)
             }
         }
     }
 
-    pub async fn upload<T>(&self, files: T) -> Result<(), UploadError>
-    where T: IntoIterator<Item = (String, Vec<u8>)> {
-        let mut length = 0;
-        let mut form = reqwest::multipart::Form::new();
-        for (name, file) in files {
-            form = form.part(
-                name.clone(),
-                reqwest::multipart::Part::bytes(file).file_name(name),
-            );
-            length += 1;
-        }
-        if length == 0 {
+    /// Upload files, given as pairs of site path and local path. File contents are
+    /// streamed from disk.
+    pub async fn upload<T>(&self, files: T) -> Result<(), NeocitiesError>
+    where T: IntoIterator<Item = (String, PathBuf)> {
+        let files = files.into_iter().collect::<Vec<_>>();
+        if files.is_empty() {
             return Ok(());
         }
         let response = self
-            .client
-            .post("https://neocities.org/api/upload")
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.api_key.as_ref().unwrap()),
-            )
-            .multipart(form)
-            .send()
-            .await?
-            .json::<GenericResponse>()
+            .send(|| async {
+                let mut form = reqwest::multipart::Form::new();
+                for (name, path) in &files {
+                    let file = File::open(path).await?;
+                    let size = file.metadata().await?.len();
+                    let body = Body::wrap_stream(ReaderStream::new(file));
+                    form = form.part(
+                        name.clone(),
+                        reqwest::multipart::Part::stream_with_length(body, size)
+                            .file_name(name.clone()),
+                    );
+                }
+                Ok(self
+                    .request(Method::POST, "upload")
+                    .header("Authorization", self.authorization())
+                    .multipart(form))
+            })
             .await?;
-        match response.error_type.as_deref() {
-            Some("invalid_auth") => Err(UploadError::InvalidAuth),
-            Some("invalid_file_type") => Err(UploadError::InvalidFileType),
-            Some(error) => {
-                unimplemented!("(upload) {} : {}", error, response.message.unwrap())
-            }
-            _ => Ok(()),
-        }
+        let (status, response) = parse::<GenericResponse>(response).await?;
+        check(status, response.error_type, response.message)
     }
 
-    pub async fn delete<T>(&self, files: T) -> Result<(), DeleteError>
+    pub async fn delete<T>(&self, files: T) -> Result<(), NeocitiesError>
     where T: IntoIterator<Item = String> {
-        let mut length = 0;
-        let mut form = reqwest::multipart::Form::new();
-        for name in files {
-            if name == "index.html" {
-                continue;
-            }
-            form = form.text("filenames[]", name);
-            length += 1;
-        }
-        if length == 0 {
+        let files =
+            files.into_iter().filter(|name| name != "index.html").collect::<Vec<_>>();
+        if files.is_empty() {
             return Ok(());
         }
         let response = self
-            .client
-            .post("https://neocities.org/api/delete")
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.api_key.as_ref().unwrap()),
-            )
-            .multipart(form)
-            .send()
-            .await?
-            .json::<GenericResponse>()
+            .send(|| async {
+                let mut form = reqwest::multipart::Form::new();
+                for name in &files {
+                    form = form.text("filenames[]", name.clone());
+                }
+                Ok(self
+                    .request(Method::POST, "delete")
+                    .header("Authorization", self.authorization())
+                    .multipart(form))
+            })
             .await?;
-        match response.error_type.as_deref() {
-            Some("missing_files") => Err(DeleteError::MissingFiles),
-            Some("invalid_auth") => Err(DeleteError::InvalidAuth),
-            Some(error) => {
-                unimplemented!("(delete) {} : {}", error, response.message.unwrap())
-            }
-            _ => Ok(()),
-        }
+        let (status, response) = parse::<GenericResponse>(response).await?;
+        check(status, response.error_type, response.message)
+    }
+
+    /// Move the file at `path` to `new_path` on the site, without uploading it again.
+    pub async fn rename(
+        &self,
+        path: &str,
+        new_path: &str,
+    ) -> Result<(), NeocitiesError> {
+        let response = self
+            .send(|| async {
+                let form = reqwest::multipart::Form::new()
+                    .text("path", path.to_string())
+                    .text("new_path", new_path.to_string());
+                Ok(self
+                    .request(Method::POST, "rename")
+                    .header("Authorization", self.authorization())
+                    .multipart(form))
+            })
+            .await?;
+        let (status, response) = parse::<GenericResponse>(response).await?;
+        check(status, response.error_type, response.message)
     }
 
-    pub async fn list(&self) -> Result<Vec<Entry>, ListError> {
+    /// Information about the site `sitename`, or about the logged in site if `None`.
+    /// Other sites are queried without authentication.
+    pub async fn info(
+        &self,
+        sitename: Option<&str>,
+    ) -> Result<SiteInfo, NeocitiesError> {
         let response = self
-            .client
-            .get("https://neocities.org/api/list")
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.api_key.as_ref().unwrap()),
+            .send(|| async {
+                Ok(match sitename {
+                    Some(sitename) => self
+                        .request(Method::GET, "info")
+                        .query(&[("sitename", sitename)]),
+                    None => self
+                        .request(Method::GET, "info")
+                        .header("Authorization", self.authorization()),
+                })
+            })
+            .await?;
+        let (status, response) = parse::<InfoResponse>(response).await?;
+        check(status, response.error_type, response.message)?;
+        response.info.ok_or_else(|| {
+            NeocitiesError::new(
+                status,
+                "invalid_response".to_string(),
+                Some("the response has no info".to_string()),
             )
-            .send()
-            .await?
-            .json::<ListResponse>()
+        })
+    }
+
+    /// Download `name` from the public site at `site_url` into `path`, returning the
+    /// SHA1 hash of the downloaded contents.
+    pub async fn download(
+        &self,
+        site_url: &str,
+        name: &str,
+        path: impl AsRef<Path>,
+    ) -> Result<String, NeocitiesError> {
+        let invalid_url = |message: String| {
+            NeocitiesError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_url".to_string(),
+                Some(message),
+            )
+        };
+        let mut url = reqwest::Url::parse(site_url)
+            .map_err(|error| invalid_url(error.to_string()))?;
+        // Each part of `name` is percent-encoded, so that names with `#`, `?` or spaces
+        // are not read as part of the URL.
+        url.path_segments_mut()
+            .map_err(|()| invalid_url(format!("{} cannot have a path", site_url)))?
+            .pop_if_empty()
+            .extend(name.split('/'));
+        let mut response = self
+            .send(|| async {
+                self.requests.fetch_add(1, Ordering::Relaxed);
+                Ok(self.client.get(url.clone()))
+            })
             .await?;
-        match response.error_type.as_deref() {
-            Some("invalid_auth") => Err(ListError::InvalidAuth),
-            Some(error) => {
-                unimplemented!("(list) {} : {}", error, response.message.unwrap())
-            }
-            _ => Ok(response.files.unwrap()),
+        let status = response.status();
+        if !status.is_success() {
+            return Err(NeocitiesError::new(status, "http_error".to_string(), None));
         }
+        let mut file = File::create(path).await?;
+        let mut hasher = Sha1::new();
+        while let Some(chunk) = response.chunk().await? {
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+        }
+        file.sync_all().await?;
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// List the files and directories of the site, or only those inside the directory
+    /// `path`.
+    pub async fn list(&self, path: Option<&str>) -> Result<Vec<Entry>, NeocitiesError> {
+        let response = self
+            .send(|| async {
+                let request = self
+                    .request(Method::GET, "list")
+                    .header("Authorization", self.authorization());
+                Ok(match path {
+                    Some(path) => request.query(&[("path", path)]),
+                    None => request,
+                })
+            })
+            .await?;
+        let (status, response) = parse::<ListResponse>(response).await?;
+        check(status, response.error_type, response.message)?;
+        Ok(response.files)
     }
 }
@@ -0,0 +1,129 @@
+use std::path::{Component, Path};
+
+use futures::{stream, StreamExt, TryStreamExt};
+use tokio::{fs, io};
+
+use crate::{
+    neocities::Neocities,
+    state::{now, temp_path, write_state_file, FileState, State},
+    sync::{hash_file, mtime, SyncError, DEFAULT_CONCURRENCY},
+};
+
+#[derive(Debug)]
+pub struct PullOptions {
+    /// Username of the site, recorded in the state file.
+    pub username: Option<String>,
+    /// Maximum number of downloads in flight at once.
+    pub concurrency: usize,
+}
+
+impl Default for PullOptions {
+    fn default() -> Self {
+        Self { username: None, concurrency: DEFAULT_CONCURRENCY }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PullStats {
+    pub downloaded: Vec<String>,
+    /// Local files which already matched the site.
+    pub unchanged: Vec<String>,
+}
+
+/// Download `name` into `file_path` unless the local file already has `hash`. Returns
+/// whether the file was downloaded.
+async fn pull_file(
+    neocities: &Neocities,
+    site_url: &str,
+    name: &str,
+    hash: &str,
+    file_path: &Path,
+) -> Result<bool, SyncError> {
+    match hash_file(file_path).await {
+        Ok((local_hash, _)) if local_hash == hash => return Ok(false),
+        Ok(_) => {}
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+        Err(error) => return Err(error.into()),
+    }
+    if let Some(parent) = file_path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    // Download next to the file, so that a failed download never leaves a partial file
+    // behind.
+    let temp_path = temp_path(file_path);
+    let downloaded = match neocities.download(site_url, name, &temp_path).await {
+        Ok(downloaded) => downloaded,
+        Err(error) => {
+            fs::remove_file(&temp_path).await.ok();
+            return Err(error.into());
+        }
+    };
+    if downloaded != hash {
+        fs::remove_file(&temp_path).await?;
+        return Err(SyncError::HashMismatch {
+            path: name.to_string(),
+            expected: hash.to_string(),
+            actual: downloaded,
+        });
+    }
+    fs::rename(&temp_path, file_path).await?;
+    Ok(true)
+}
+
+/// Download the files of the site at `site_url` into `path`, and write a state file
+/// which matches them, so that syncing the directory afterwards changes nothing.
+pub async fn pull(
+    neocities: &Neocities,
+    site_url: &str,
+    path: impl AsRef<Path>,
+    state_path: impl AsRef<Path>,
+    options: &PullOptions,
+) -> Result<PullStats, SyncError> {
+    let path = path.as_ref();
+    let state_path_relative_to_path = pathdiff::diff_paths(&state_path, path);
+    let files = neocities.list(None).await?.into_iter().filter_map(|entry| {
+        let name = Path::new(&entry.path);
+        // Paths which would escape `path` are never written, and neither is the state
+        // file. Directories have no hash.
+        if !name.components().all(|it| matches!(it, Component::Normal(_)))
+            || state_path_relative_to_path.as_deref() == Some(name)
+        {
+            return None;
+        }
+        Some((entry.path, entry.sha1_hash?))
+    });
+    let mut pulled = stream::iter(files)
+        .map(|(name, hash)| async move {
+            let file_path = path.join(&name);
+            let is_downloaded =
+                pull_file(neocities, site_url, &name, &hash, &file_path).await?;
+            let metadata = fs::metadata(&file_path).await?;
+            let file_state = FileState {
+                hash,
+                size: Some(metadata.len()),
+                mtime: mtime(&metadata),
+                uploaded_at: None,
+            };
+            Ok::<_, SyncError>((name, file_state, is_downloaded))
+        })
+        .buffer_unordered(options.concurrency.max(1))
+        .try_collect::<Vec<_>>()
+        .await?;
+    pulled.sort_by(|(a, ..), (b, ..)| a.cmp(b));
+    let mut stats = PullStats::default();
+    let mut state = State {
+        username: options.username.clone(),
+        last_sync: Some(now()),
+        ..State::default()
+    };
+    for (name, file_state, is_downloaded) in pulled {
+        if is_downloaded {
+            stats.downloaded.push(name.clone());
+        } else {
+            stats.unchanged.push(name.clone());
+        }
+        state.files.insert(name, file_state);
+    }
+    write_state_file(&state, state_path).await?;
+    Ok(stats)
+}
@@ -1,49 +1,145 @@
-use std::path::Path;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use fxhash::FxHashMap;
+use serde::{Deserialize, Serialize};
 use tokio::{
-    fs::File,
-    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    fs::{self, File},
+    io::{self, AsyncWriteExt},
 };
 
-use crate::neocities::{ListError, Neocities};
+use crate::neocities::{Neocities, NeocitiesError};
+
+/// Version of the state file format written by this version of neocities-sync.
+pub const STATE_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileState {
+    /// SHA1 hash of the file contents.
+    pub hash: String,
+    pub size: Option<u64>,
+    /// Modification time of the local file, in nanoseconds since the Unix epoch.
+    pub mtime: Option<u64>,
+    /// When the file was last uploaded, in seconds since the Unix epoch.
+    pub uploaded_at: Option<u64>,
+}
+
+impl FileState {
+    pub fn new(hash: String) -> Self {
+        Self { hash, size: None, mtime: None, uploaded_at: None }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct State {
+    pub version: u32,
+    /// Username of the site this state belongs to, if known.
+    pub username: Option<String>,
+    /// Directory on the site the files are synced to, if not the root. Paths in
+    /// `files` are relative to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_dir: Option<String>,
+    /// Whether the site may upload every file type, learned from neocities accepting
+    /// or rejecting files which only supporters may upload.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub supporter: Option<bool>,
+    /// When the last sync finished, in seconds since the Unix epoch.
+    pub last_sync: Option<u64>,
+    /// Files on the site, keyed by path. Sorted, so that the state file diffs well.
+    pub files: BTreeMap<String, FileState>,
+}
 
-pub async fn read_state_file(
-    path: impl AsRef<Path>,
-) -> Result<FxHashMap<String, String>, io::Error> {
-    let mut state = FxHashMap::default();
-    let mut lines = BufReader::new(File::open(path).await?).lines();
-    while let Some(line) = lines.next_line().await? {
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            version: STATE_VERSION,
+            username: None,
+            remote_dir: None,
+            supporter: None,
+            last_sync: None,
+            files: BTreeMap::new(),
+        }
+    }
+}
+
+/// Seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Parse the legacy state format, which has one `path:hash` line per file.
+fn parse_legacy_state(contents: &str) -> State {
+    let mut state = State::default();
+    for line in contents.lines() {
         let Some((key, value)) = line.split_once(':') else {
             continue;
         };
-        state.insert(key.to_string(), value.to_string());
+        state.files.insert(key.to_string(), FileState::new(value.to_string()));
     }
-    Ok(state)
+    state
 }
 
-pub async fn write_state_file(
-    state: &FxHashMap<String, String>,
-    path: impl AsRef<Path>,
-) -> io::Result<()> {
-    let mut file = File::create(path).await?;
-    for (key, value) in state {
-        file.write_all(key.as_bytes()).await?;
-        file.write_all(b":").await?;
-        file.write_all(value.as_bytes()).await?;
-        file.write_all(b"\n").await?;
+/// Read a state file. State files in the legacy line format are migrated.
+pub async fn read_state_file(path: impl AsRef<Path>) -> Result<State, io::Error> {
+    let contents = fs::read_to_string(path).await?;
+    if !contents.trim_start().starts_with('{') {
+        return Ok(parse_legacy_state(&contents));
+    }
+    let state: State = serde_json::from_str(&contents)?;
+    if state.version > STATE_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "state file version {} is newer than the supported version {}",
+                state.version, STATE_VERSION
+            ),
+        ));
     }
-    Ok(())
+    Ok(state)
 }
 
+/// Path of the temporary file the state is written to before it replaces the state
+/// file.
+pub fn temp_path(path: impl AsRef<Path>) -> PathBuf {
+    let mut temp_path = path.as_ref().as_os_str().to_owned();
+    temp_path.push(".tmp");
+    temp_path.into()
+}
+
+/// Write the state file atomically, so that an interrupted write never leaves a
+/// truncated state behind.
+pub async fn write_state_file(state: &State, path: impl AsRef<Path>) -> io::Result<()> {
+    let temp_path = temp_path(&path);
+    let mut contents = serde_json::to_vec_pretty(state)?;
+    contents.push(b'\n');
+    let mut file = File::create(&temp_path).await?;
+    file.write_all(&contents).await?;
+    file.sync_all().await?;
+    fs::rename(temp_path, path).await
+}
+
+/// Fetch the state of the site, or of `remote_dir` on the site with paths relative to
+/// it.
 pub async fn fetch_state(
     neocities: &Neocities,
-) -> Result<FxHashMap<String, String>, ListError> {
-    let mut state = FxHashMap::default();
-    let response = neocities.list().await?;
+    remote_dir: Option<&str>,
+) -> Result<State, NeocitiesError> {
+    let mut state =
+        State { remote_dir: remote_dir.map(str::to_string), ..State::default() };
+    let prefix = remote_dir.map(|it| format!("{}/", it));
+    let response = neocities.list(remote_dir).await?;
     for file in response {
+        let path = match &prefix {
+            Some(prefix) => match file.path.strip_prefix(prefix) {
+                Some(path) => path.to_string(),
+                None => continue,
+            },
+            None => file.path,
+        };
         if let Some(sha1_hash) = file.sha1_hash {
-            state.insert(file.path, sha1_hash);
+            state.files.insert(path, FileState::new(sha1_hash));
         }
     }
     Ok(state)
@@ -1,18 +1,29 @@
 use std::{
     cell::RefCell,
-    io::Write,
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    fs::Metadata,
     path::{Path, PathBuf},
+    time::UNIX_EPOCH,
 };
 
-use futures::{future::try_join_all, TryFutureExt};
-use fxhash::FxHashMap;
+use clap::ValueEnum;
+use futures::{stream, StreamExt, TryStreamExt};
+use serde::Serialize;
 use sha1::{Digest, Sha1};
-use tokio::{fs, try_join};
+use tokio::{
+    fs::File,
+    io::{self, AsyncReadExt},
+};
 use walkdir::WalkDir;
 
 use crate::{
-    neocities::{DeleteError, ListError, Neocities, UploadError},
-    state::{fetch_state, read_state_file, write_state_file},
+    filter::Filter,
+    neocities::{Neocities, NeocitiesError},
+    state::{
+        fetch_state, now, read_state_file, temp_path, write_state_file, FileState,
+        State,
+    },
 };
 
 #[rustfmt::skip]
@@ -27,46 +38,73 @@ const ALLOWED_FILE_TYPES: &[&str] = &[
 ];
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum SyncError {
-    InvalidAuth,
-    InvalidFileType,
-    MissingFiles,
-    ReqwestError(reqwest::Error),
+    NeocitiesError(NeocitiesError),
+    /// The sync would delete more files than `SyncOptions::max_delete` allows.
+    TooManyDeletions {
+        planned: usize,
+        max: usize,
+    },
+    IgnoreError(ignore::Error),
+    /// The state file exists, but could not be read.
+    StateError(std::io::Error),
+    /// The state file was written for another site.
+    StateMismatch {
+        expected: String,
+        actual: String,
+    },
+    /// The site was changed outside of neocities-sync since the last sync.
+    RemoteDiverged {
+        paths: Vec<String>,
+    },
+    /// A downloaded file does not have the hash neocities reported for it.
+    HashMismatch {
+        path: String,
+        expected: String,
+        actual: String,
+    },
     IOError(std::io::Error),
 }
 
-impl From<ListError> for SyncError {
-    fn from(error: ListError) -> Self {
-        match error {
-            ListError::InvalidAuth => Self::InvalidAuth,
-            ListError::ReqwestError(error) => Self::ReqwestError(error),
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NeocitiesError(error) => error.fmt(f),
+            Self::TooManyDeletions { planned, max } => write!(
+                f,
+                "refusing to delete {} files, at most {} are allowed",
+                planned, max
+            ),
+            Self::IgnoreError(error) => error.fmt(f),
+            Self::StateError(error) => error.fmt(f),
+            Self::StateMismatch { expected, actual } => {
+                write!(f, "the state file belongs to {}, not {}", expected, actual)
+            }
+            Self::RemoteDiverged { paths } => write!(
+                f,
+                "{} files were changed on neocities since the last sync",
+                paths.len()
+            ),
+            Self::HashMismatch { path, expected, actual } => write!(
+                f,
+                "{} has hash {}, but neocities reported {}",
+                path, actual, expected
+            ),
+            Self::IOError(error) => error.fmt(f),
         }
     }
 }
 
-impl From<UploadError> for SyncError {
-    fn from(error: UploadError) -> Self {
-        match error {
-            UploadError::InvalidFileType => Self::InvalidFileType,
-            UploadError::InvalidAuth => Self::InvalidAuth,
-            UploadError::ReqwestError(error) => Self::ReqwestError(error),
-        }
+impl From<NeocitiesError> for SyncError {
+    fn from(error: NeocitiesError) -> Self {
+        Self::NeocitiesError(error)
     }
 }
 
-impl From<DeleteError> for SyncError {
-    fn from(error: DeleteError) -> Self {
-        match error {
-            DeleteError::MissingFiles => Self::MissingFiles,
-            DeleteError::InvalidAuth => Self::InvalidAuth,
-            DeleteError::ReqwestError(error) => Self::ReqwestError(error),
-        }
-    }
-}
-
-impl From<reqwest::Error> for SyncError {
-    fn from(error: reqwest::Error) -> Self {
-        Self::ReqwestError(error)
+impl From<ignore::Error> for SyncError {
+    fn from(error: ignore::Error) -> Self {
+        Self::IgnoreError(error)
     }
 }
 
@@ -76,88 +114,811 @@ impl From<std::io::Error> for SyncError {
     }
 }
 
+/// Hash a file incrementally, returning its SHA1 hash and size.
+pub async fn hash_file(path: impl AsRef<Path>) -> io::Result<(String, u64)> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha1::new();
+    let mut buffer = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let length = file.read(&mut buffer).await?;
+        if length == 0 {
+            break;
+        }
+        hasher.update(&buffer[..length]);
+        size += length as u64;
+    }
+    Ok((format!("{:x}", hasher.finalize()), size))
+}
+
+/// Modification time of a file, in nanoseconds since the Unix epoch.
+pub fn mtime(metadata: &Metadata) -> Option<u64> {
+    let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    u64::try_from(mtime.as_nanos()).ok()
+}
+
 async fn process(
     path: impl AsRef<Path>,
-    current_state: &FxHashMap<String, String>,
-    new_state: &RefCell<FxHashMap<String, String>>,
+    current_state: &State,
+    new_state: &RefCell<BTreeMap<String, FileState>>,
     state_path_relative_to_path: &Option<PathBuf>,
-    to_be_uploaded: &RefCell<Vec<(String, Vec<u8>)>>,
+    rehash: bool,
     subpath: PathBuf,
 ) -> Result<(), SyncError> {
-    if state_path_relative_to_path.as_deref().is_some_and(|it| it == subpath) {
+    let file_path = subpath;
+    let subpath = pathdiff::diff_paths(&file_path, path.as_ref()).unwrap();
+    if state_path_relative_to_path
+        .as_deref()
+        .is_some_and(|it| it == subpath || temp_path(it) == subpath)
+    {
         return Ok(());
     }
-    let file = fs::read(&subpath).await?;
-    let subpath = pathdiff::diff_paths(&subpath, path.as_ref()).unwrap();
-    let mut hasher = Sha1::new();
-    hasher.write_all(&file)?;
-    let new_hash = format!("{:x}", hasher.finalize());
-    let old_hash = current_state.get(subpath.to_str().unwrap());
-    let is_modified = Some(&new_hash) != old_hash;
+    let metadata = tokio::fs::metadata(&file_path).await?;
+    let modified = mtime(&metadata);
+    let old = current_state.files.get(subpath.to_str().unwrap());
+    // The cached hash is trusted if neither the size nor the mtime changed.
+    let cached = old.filter(|it| {
+        !rehash
+            && modified.is_some()
+            && it.mtime == modified
+            && it.size == Some(metadata.len())
+    });
+    let (new_hash, size) = match cached {
+        Some(old) => (old.hash.clone(), metadata.len()),
+        None => hash_file(&file_path).await?,
+    };
+    let uploaded_at =
+        old.filter(|it| it.hash == new_hash).and_then(|it| it.uploaded_at);
+    let file_state =
+        FileState { hash: new_hash, size: Some(size), mtime: modified, uploaded_at };
     let new_state = &mut *new_state.borrow_mut();
-    new_state.insert(subpath.to_str().unwrap().to_string(), new_hash);
-    if is_modified {
-        let to_be_uploaded = &mut *to_be_uploaded.borrow_mut();
-        to_be_uploaded.push((subpath.to_str().unwrap().to_string(), file));
-    }
+    new_state.insert(subpath.to_str().unwrap().to_string(), file_state);
     Ok(())
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadReason {
+    New,
+    Modified,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Upload {
+    pub path: String,
+    pub reason: UploadReason,
+    pub size: u64,
+    pub hash: String,
+}
+
+/// A new local file with the same contents as a deleted one, which is moved on the
+/// site instead of being uploaded again.
+#[derive(Debug, Serialize)]
+pub struct Rename {
+    pub from: String,
+    pub to: String,
+    pub size: u64,
+    pub hash: String,
+}
+
+/// Maximum number of files hashed at once.
+const MAX_OPEN_FILES: usize = 64;
+
+pub const DEFAULT_BATCH_SIZE: u64 = 50 * 1024 * 1024;
+pub const DEFAULT_BATCH_FILES: usize = 100;
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+/// What to do when the site was changed outside of neocities-sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum VerifyRemote {
+    /// Abort the sync.
+    Refuse,
+    /// Print the changed files, and sync as usual.
+    Warn,
+    /// Sync against the files on the site instead of the state file, so that remote
+    /// changes are overwritten and files deleted on the site are uploaded again.
+    Reconcile,
+}
+
+#[derive(Debug)]
+pub struct SyncOptions {
+    /// Username of the site. A state file recorded for another site is refused.
+    pub username: Option<String>,
+    /// Directory on the site to sync to, without leading or trailing slashes. Only
+    /// files inside it are deleted. If `None`, the whole site is synced.
+    pub remote_dir: Option<String>,
+    /// Fetch the state from the site instead of reading the state file.
+    pub refetch_state: bool,
+    /// Leave out files which only supporters may upload. If `None`, they are left out
+    /// once neocities has rejected a file because of its type.
+    pub ignore_disallowed_file_types: Option<bool>,
+    /// Stop after planning, without touching the site or the state file.
+    pub dry_run: bool,
+    /// Compare the state with the site before syncing.
+    pub verify_remote: Option<VerifyRemote>,
+    /// Hash every file, instead of trusting the state for files whose size and mtime
+    /// did not change.
+    pub rehash: bool,
+    /// Globs of paths to leave out of the sync, in addition to `.neocitiesignore`.
+    pub exclude: Vec<String>,
+    /// Globs of paths to sync even if they are excluded or ignored.
+    pub include: Vec<String>,
+    /// Never delete remote files.
+    pub no_delete: bool,
+    /// Globs of remote paths which are never deleted.
+    pub keep: Vec<String>,
+    /// Abort the sync if it would delete more than this many files.
+    pub max_delete: Option<usize>,
+    /// Maximum total size in bytes of the files sent in a single upload request.
+    pub batch_size: u64,
+    /// Maximum number of files sent in a single upload request.
+    pub batch_files: usize,
+    /// Maximum number of upload requests in flight at once.
+    pub concurrency: usize,
+}
+
+impl SyncOptions {
+    /// Path on the site of the local file at `subpath`.
+    pub fn remote_path(&self, subpath: &str) -> String {
+        match &self.remote_dir {
+            Some(remote_dir) => format!("{}/{}", remote_dir, subpath),
+            None => subpath.to_string(),
+        }
+    }
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        Self {
+            username: None,
+            remote_dir: None,
+            refetch_state: false,
+            ignore_disallowed_file_types: None,
+            dry_run: false,
+            rehash: false,
+            verify_remote: None,
+            exclude: vec![],
+            include: vec![],
+            no_delete: false,
+            keep: vec![],
+            max_delete: None,
+            batch_size: DEFAULT_BATCH_SIZE,
+            batch_files: DEFAULT_BATCH_FILES,
+            concurrency: DEFAULT_CONCURRENCY,
+        }
+    }
+}
+
+/// Split `files` into batches of at most `max_files` files and `max_size` bytes. A
+/// file larger than `max_size` is sent in a batch of its own.
+pub fn into_batches<T>(
+    files: Vec<T>,
+    size_of: impl Fn(&T) -> u64,
+    max_size: u64,
+    max_files: usize,
+) -> Vec<Vec<T>> {
+    let mut batches = vec![];
+    let mut batch = vec![];
+    let mut batch_size = 0;
+    for file in files {
+        let size = size_of(&file);
+        if !batch.is_empty()
+            && (batch.len() >= max_files || batch_size + size > max_size)
+        {
+            batches.push(std::mem::take(&mut batch));
+            batch_size = 0;
+        }
+        batch_size += size;
+        batch.push(file);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+#[derive(Debug, Default, Serialize)]
 pub struct SyncStats {
-    pub uploaded: usize,
-    pub deleted: usize,
+    pub uploaded: Vec<Upload>,
+    pub deleted: Vec<String>,
+    pub ignored: Vec<String>,
+    /// Remote files which were not deleted because of `--no-delete` or `--keep`.
+    pub kept: Vec<String>,
+    /// Remote files which were changed outside of neocities-sync, found by
+    /// `SyncOptions::verify_remote`.
+    pub diverged: Vec<String>,
+    /// Files which were missing on the site, although the state listed them. They were
+    /// uploaded again if they exist locally.
+    pub reconciled: Vec<String>,
+    /// Files which neocities refused to accept because of their file type.
+    pub rejected: Vec<String>,
+    /// Files which were moved on the site instead of being uploaded again.
+    pub renamed: Vec<Rename>,
 }
 
-pub async fn sync(
+/// Read the state file, falling back to the state of the site if there is none.
+/// Returns whether the state was fetched from the site.
+async fn load_state(
     neocities: &Neocities,
-    path: impl AsRef<Path>,
     state_path: impl AsRef<Path>,
-    ignore_disllowed_file_types: bool,
-) -> Result<SyncStats, SyncError> {
-    let current_state = if let Ok(state) = read_state_file(&state_path).await {
-        state
+    options: &SyncOptions,
+) -> Result<(State, bool), SyncError> {
+    let (state, is_fetched) = if options.refetch_state {
+        (fetch_state(neocities, options.remote_dir.as_deref()).await?, true)
     } else {
-        fetch_state(neocities).await?
+        match read_state_file(&state_path).await {
+            // A state file of another directory on the site says nothing about this
+            // one.
+            Ok(state) if state.remote_dir != options.remote_dir => {
+                (fetch_state(neocities, options.remote_dir.as_deref()).await?, true)
+            }
+            Ok(state) => (state, false),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                (fetch_state(neocities, options.remote_dir.as_deref()).await?, true)
+            }
+            Err(error) => return Err(SyncError::StateError(error)),
+        }
     };
-    let new_state: RefCell<FxHashMap<String, String>> = Default::default();
-    let state_path_relative_to_path = pathdiff::diff_paths(&state_path, &path);
-    let to_be_uploaded: RefCell<Vec<(String, Vec<u8>)>> = Default::default();
+    check_site(&state, options.username.as_deref())?;
+    Ok((state, is_fetched))
+}
+
+/// Paths whose remote hash differs from the state, including files which were added
+/// or deleted on the site. Ignored and kept paths are left out, since a sync does not
+/// touch them.
+fn diverged_paths(state: &State, remote: &State, filter: &Filter) -> Vec<String> {
+    let paths: BTreeSet<&String> =
+        state.files.keys().chain(remote.files.keys()).collect();
+    paths
+        .into_iter()
+        .filter(|it| {
+            state.files.get(*it).map(|it| &it.hash)
+                != remote.files.get(*it).map(|it| &it.hash)
+        })
+        .filter(|it| !filter.is_ignored(it, false) && !filter.is_kept(it))
+        .cloned()
+        .collect()
+}
+
+/// A state file of another site would make the sync skip files which that site
+/// happens to have.
+pub fn check_site(state: &State, username: Option<&str>) -> Result<(), SyncError> {
+    if let (Some(expected), Some(actual)) = (&state.username, username) {
+        if expected != actual {
+            return Err(SyncError::StateMismatch {
+                expected: expected.clone(),
+                actual: actual.to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn is_of_allowed_file_type(path: impl AsRef<Path>) -> bool {
+    path.as_ref()
+        .extension()
+        .is_some_and(|it| ALLOWED_FILE_TYPES.contains(&it.to_str().unwrap()))
+}
+
+/// Whether to leave out files which only supporters may upload. Unless the options
+/// say so, they are left out once the state records that the site is not a supporter.
+fn ignores_disallowed_file_types(state: &State, options: &SyncOptions) -> bool {
+    options.ignore_disallowed_file_types.unwrap_or(state.supporter == Some(false))
+}
+
+/// The local files of a sync, compared against the state.
+struct Scan {
+    /// State of every local file which is not ignored.
+    files: BTreeMap<String, FileState>,
+    ignored: Vec<String>,
+    filter: Filter,
+}
+
+/// Walk and hash the local files, leaving out the state file and ignored files.
+async fn scan(
+    path: impl AsRef<Path>,
+    state_path: impl AsRef<Path>,
+    current_state: &State,
+    ignore_disallowed_file_types: bool,
+    options: &SyncOptions,
+) -> Result<Scan, SyncError> {
+    let path = path.as_ref();
+    let new_state: RefCell<BTreeMap<String, FileState>> = Default::default();
+    let state_path_relative_to_path = pathdiff::diff_paths(&state_path, path);
+    let filter = RefCell::new(Filter::new(
+        path,
+        &options.exclude,
+        &options.include,
+        &options.keep,
+    )?);
+    let mut ignored = vec![];
     let mut futs = vec![];
-    for subpath in WalkDir::new(&path) {
+    let walk = WalkDir::new(path).into_iter().filter_entry(|entry| {
+        let subpath = pathdiff::diff_paths(entry.path(), path).unwrap();
+        let is_dir = entry.file_type().is_dir();
+        let filter = filter.borrow();
+        // Ignored directories are still walked if an include could bring back a file
+        // inside them. Their files are checked one by one.
+        entry.depth() == 0
+            || !filter.is_ignored(&subpath, is_dir)
+            || is_dir && filter.may_include_below(subpath.to_str().unwrap())
+    });
+    for subpath in walk {
         let subpath = subpath.map_err(|err| err.into_io_error().unwrap())?;
         let subpath = subpath.path().to_path_buf();
-        let is_of_allowed_file_type = subpath
-            .extension()
-            .is_some_and(|it| ALLOWED_FILE_TYPES.contains(&it.to_str().unwrap()));
         if subpath.is_dir() {
+            filter.borrow_mut().load(pathdiff::diff_paths(&subpath, path).unwrap())?;
             continue;
         }
-        if ignore_disllowed_file_types && !is_of_allowed_file_type {
-            eprintln!("[ignored] {}", subpath.display());
+        if ignore_disallowed_file_types && !is_of_allowed_file_type(&subpath) {
+            let subpath = pathdiff::diff_paths(&subpath, path).unwrap();
+            ignored.push(subpath.to_str().unwrap().to_string());
             continue;
         }
         futs.push(process(
-            &path,
-            &current_state,
+            path,
+            current_state,
             &new_state,
             &state_path_relative_to_path,
-            &to_be_uploaded,
+            options.rehash,
             subpath,
         ));
     }
-    try_join_all(futs).await?;
-    let new_state = new_state.into_inner();
-    let to_be_uploaded = to_be_uploaded.into_inner();
-    let to_be_deleted = current_state
-        .keys()
-        .filter(|&subpath| !new_state.contains_key(subpath))
-        .cloned()
-        .collect::<Vec<_>>();
-    let stats =
-        SyncStats { uploaded: to_be_uploaded.len(), deleted: to_be_deleted.len() };
-    let upload_fut = neocities.upload(to_be_uploaded).err_into::<SyncError>();
-    let delete_fut = neocities.delete(to_be_deleted).err_into::<SyncError>();
-    try_join!(upload_fut, delete_fut)?;
+    stream::iter(futs).buffer_unordered(MAX_OPEN_FILES).try_collect::<()>().await?;
+    Ok(Scan { files: new_state.into_inner(), ignored, filter: filter.into_inner() })
+}
+
+/// Local files whose hash differs from the state, sorted by path.
+fn plan_uploads(files: &BTreeMap<String, FileState>, state: &State) -> Vec<Upload> {
+    let mut uploads = vec![];
+    for (path, file_state) in files {
+        let old = state.files.get(path);
+        if old.is_some_and(|it| it.hash == file_state.hash) {
+            continue;
+        }
+        uploads.push(Upload {
+            path: path.clone(),
+            reason: if old.is_some() {
+                UploadReason::Modified
+            } else {
+                UploadReason::New
+            },
+            size: file_state.size.unwrap_or_default(),
+            hash: file_state.hash.clone(),
+        });
+    }
+    uploads
+}
+
+/// Pair new files with deleted files of the same hash, taking them out of `uploads`
+/// and `deletes`. A deleted file with the same file name is preferred, so that moving
+/// a directory of identical files keeps their names. The root `index.html` is never
+/// moved, since neocities does not allow deleting it.
+fn plan_renames(
+    uploads: &mut Vec<Upload>,
+    deletes: &mut Vec<String>,
+    current_state: &State,
+    options: &SyncOptions,
+) -> Vec<Rename> {
+    let mut deleted_by_hash = BTreeMap::<&str, Vec<&String>>::new();
+    for path in deletes.iter() {
+        if options.remote_path(path) == "index.html" {
+            continue;
+        }
+        deleted_by_hash.entry(&current_state.files[path].hash).or_default().push(path);
+    }
+    let mut renames = vec![];
+    uploads.retain(|upload| {
+        if upload.reason != UploadReason::New {
+            return true;
+        }
+        let Some(candidates) = deleted_by_hash.get_mut(upload.hash.as_str()) else {
+            return true;
+        };
+        if candidates.is_empty() {
+            return true;
+        }
+        let index = candidates
+            .iter()
+            .position(|it| {
+                Path::new(it).file_name() == Path::new(&upload.path).file_name()
+            })
+            .unwrap_or(0);
+        renames.push(Rename {
+            from: candidates.remove(index).clone(),
+            to: upload.path.clone(),
+            size: upload.size,
+            hash: upload.hash.clone(),
+        });
+        false
+    });
+    deletes.retain(|path| renames.iter().all(|it| it.from != *path));
+    renames
+}
+
+pub async fn sync(
+    neocities: &Neocities,
+    path: impl AsRef<Path>,
+    state_path: impl AsRef<Path>,
+    options: &SyncOptions,
+) -> Result<SyncStats, SyncError> {
+    let path = path.as_ref();
+    let state_path = state_path.as_ref();
+    let (mut current_state, is_fetched) =
+        load_state(neocities, state_path, options).await?;
+    let username = options.username.clone().or(current_state.username.clone());
+    let ignore_disallowed_file_types =
+        ignores_disallowed_file_types(&current_state, options);
+    let Scan { files: local_files, ignored, filter } =
+        scan(path, state_path, &current_state, ignore_disallowed_file_types, options)
+            .await?;
+    let mut diverged = vec![];
+    if let Some(verify_remote) = options.verify_remote.filter(|_| !is_fetched) {
+        let remote = fetch_state(neocities, options.remote_dir.as_deref()).await?;
+        diverged = diverged_paths(&current_state, &remote, &filter);
+        match verify_remote {
+            VerifyRemote::Refuse if !diverged.is_empty() => {
+                return Err(SyncError::RemoteDiverged { paths: diverged });
+            }
+            VerifyRemote::Refuse | VerifyRemote::Warn => {}
+            VerifyRemote::Reconcile => {
+                // Files which were only added on the site are left alone, like in any
+                // sync, since they are not in the state.
+                for path in &diverged {
+                    if !current_state.files.contains_key(path) {
+                        continue;
+                    }
+                    match remote.files.get(path) {
+                        Some(file_state) => {
+                            current_state.files.insert(path.clone(), file_state.clone())
+                        }
+                        None => current_state.files.remove(path),
+                    };
+                }
+            }
+        }
+    }
+    let mut uploaded = plan_uploads(&local_files, &current_state);
+    let mut new_files = local_files.clone();
+    let mut to_be_deleted = vec![];
+    let mut kept = vec![];
+    for (subpath, file_state) in &current_state.files {
+        if new_files.contains_key(subpath) {
+            continue;
+        }
+        // Ignored and kept files are left alone on the site, and stay in the state.
+        if filter.is_ignored(subpath, false) {
+            new_files.insert(subpath.clone(), file_state.clone());
+        } else if options.no_delete || filter.is_kept(subpath) {
+            new_files.insert(subpath.clone(), file_state.clone());
+            kept.push(subpath.clone());
+        } else {
+            to_be_deleted.push(subpath.clone());
+        }
+    }
+    to_be_deleted.sort();
+    kept.sort();
+    let renamed =
+        plan_renames(&mut uploaded, &mut to_be_deleted, &current_state, options);
+    // Renamed files count as deletions, since their old path disappears from the site
+    // too, and a refused rename deletes it outright.
+    let planned = to_be_deleted.len() + renamed.len();
+    if let Some(max) = options.max_delete {
+        if planned > max {
+            return Err(SyncError::TooManyDeletions { planned, max });
+        }
+    }
+    if options.dry_run {
+        return Ok(SyncStats {
+            uploaded,
+            deleted: to_be_deleted,
+            ignored,
+            kept,
+            diverged,
+            reconciled: vec![],
+            rejected: vec![],
+            renamed,
+        });
+    }
+    let mut confirmed_state = State { username: username.clone(), ..current_state };
+    let (renamed, refused) = rename_files(
+        neocities,
+        renamed,
+        &mut new_files,
+        &mut confirmed_state,
+        state_path,
+        options,
+    )
+    .await?;
+    // Files neocities refused to move are uploaded and deleted instead.
+    for rename in refused {
+        to_be_deleted.push(rename.from);
+        uploaded.push(Upload {
+            path: rename.to,
+            reason: UploadReason::New,
+            size: rename.size,
+            hash: rename.hash,
+        });
+    }
+    to_be_deleted.sort();
+    uploaded.sort_by(|a, b| a.path.cmp(&b.path));
+    for upload in &uploaded {
+        new_files.get_mut(&upload.path).unwrap().uploaded_at = Some(now());
+    }
+    let mut rejected = upload_batches(
+        neocities,
+        path,
+        &uploaded,
+        &new_files,
+        &mut confirmed_state,
+        state_path,
+        options,
+    )
+    .await?;
+    let mut reconciled = vec![];
+    let deleted = match neocities
+        .delete(to_be_deleted.iter().map(|it| options.remote_path(it)))
+        .await
+    {
+        Ok(()) => to_be_deleted,
+        // The state is out of date, because files were deleted on the site. Those files
+        // are uploaded again if they exist locally, and are not deleted again otherwise.
+        Err(NeocitiesError::MissingFiles(_)) => {
+            let remote = fetch_state(neocities, options.remote_dir.as_deref()).await?;
+            let (deleted, missing): (Vec<_>, Vec<_>) =
+                to_be_deleted.into_iter().partition(|it| remote.files.contains_key(it));
+            let mut missing_uploads = plan_uploads(&local_files, &remote);
+            // Rejected files are missing too, but sending them again is no use.
+            missing_uploads.retain(|it| !rejected.contains(&it.path));
+            for upload in &missing_uploads {
+                new_files.get_mut(&upload.path).unwrap().uploaded_at = Some(now());
+            }
+            rejected.extend(
+                upload_batches(
+                    neocities,
+                    path,
+                    &missing_uploads,
+                    &new_files,
+                    &mut confirmed_state,
+                    state_path,
+                    options,
+                )
+                .await?,
+            );
+            neocities.delete(deleted.iter().map(|it| options.remote_path(it))).await?;
+            reconciled.extend(missing);
+            reconciled.extend(missing_uploads.iter().map(|it| it.path.clone()));
+            reconciled.sort();
+            uploaded.extend(missing_uploads);
+            uploaded.sort_by(|a, b| a.path.cmp(&b.path));
+            deleted
+        }
+        Err(error) => return Err(error.into()),
+    };
+    // Rejected files stay as they were, so that they are uploaded again next time.
+    rejected.sort();
+    for path in &rejected {
+        match confirmed_state.files.get(path) {
+            Some(file_state) => new_files.insert(path.clone(), file_state.clone()),
+            None => new_files.remove(path),
+        };
+    }
+    uploaded.retain(|it| rejected.binary_search(&it.path).is_err());
+    // Only free sites are refused files because of their type, and only supporters
+    // may upload the other types, so every sync which sends such a file tells.
+    let supporter = if !rejected.is_empty() {
+        Some(false)
+    } else if uploaded.iter().any(|it| !is_of_allowed_file_type(&it.path)) {
+        Some(true)
+    } else {
+        confirmed_state.supporter
+    };
+    let new_state = State {
+        username,
+        remote_dir: options.remote_dir.clone(),
+        supporter,
+        last_sync: Some(now()),
+        files: new_files,
+        ..State::default()
+    };
     write_state_file(&new_state, state_path).await?;
-    Ok(stats)
+    Ok(SyncStats {
+        uploaded,
+        deleted,
+        ignored,
+        kept,
+        diverged,
+        reconciled,
+        rejected,
+        renamed,
+    })
+}
+
+/// Move files on the site, returning the renames which were done and those which
+/// neocities refused. Renames are sent concurrently like uploads, and `state` is
+/// written after every one.
+async fn rename_files(
+    neocities: &Neocities,
+    renames: Vec<Rename>,
+    files: &mut BTreeMap<String, FileState>,
+    state: &mut State,
+    state_path: &Path,
+    options: &SyncOptions,
+) -> Result<(Vec<Rename>, Vec<Rename>), SyncError> {
+    let mut results = stream::iter(renames)
+        .map(|rename| async move {
+            let from = options.remote_path(&rename.from);
+            let result =
+                neocities.rename(&from, &options.remote_path(&rename.to)).await;
+            (rename, result)
+        })
+        .buffer_unordered(options.concurrency.max(1));
+    let mut renamed = vec![];
+    let mut refused = vec![];
+    while let Some((rename, result)) = results.next().await {
+        match result {
+            Ok(()) => {}
+            Err(error)
+                if error.api_error().is_some()
+                    && !matches!(error, NeocitiesError::InvalidAuth(_)) =>
+            {
+                refused.push(rename);
+                continue;
+            }
+            Err(error) => return Err(error.into()),
+        }
+        // The file was not uploaded, so it keeps its upload time.
+        let uploaded_at =
+            state.files.remove(&rename.from).and_then(|it| it.uploaded_at);
+        let file_state = files.get_mut(&rename.to).unwrap();
+        file_state.uploaded_at = uploaded_at;
+        state.files.insert(rename.to.clone(), file_state.clone());
+        write_state_file(state, state_path).await?;
+        renamed.push(rename);
+    }
+    renamed.sort_by(|a, b| a.to.cmp(&b.to));
+    Ok((renamed, refused))
+}
+
+/// Upload a batch of files, returning the uploaded and the rejected files. Neocities
+/// rejects the whole request if one of the files has a disallowed file type, without
+/// saying which one, so rejected batches are split in half until the offending files
+/// are found.
+async fn upload_batch<'a>(
+    neocities: &Neocities,
+    path: &Path,
+    batch: Vec<&'a Upload>,
+    options: &SyncOptions,
+) -> Result<(Vec<&'a Upload>, Vec<&'a Upload>), NeocitiesError> {
+    let mut uploaded = vec![];
+    let mut rejected = vec![];
+    let mut pending = vec![batch];
+    while let Some(mut batch) = pending.pop() {
+        let files = batch
+            .iter()
+            .map(|upload| (options.remote_path(&upload.path), path.join(&upload.path)));
+        match neocities.upload(files).await {
+            Ok(()) => uploaded.extend(batch),
+            Err(NeocitiesError::InvalidFileType(_)) if batch.len() > 1 => {
+                let half = batch.split_off(batch.len() / 2);
+                pending.push(half);
+                pending.push(batch);
+            }
+            Err(NeocitiesError::InvalidFileType(_)) => rejected.extend(batch),
+            Err(error) => return Err(error),
+        }
+    }
+    Ok((uploaded, rejected))
+}
+
+/// Upload files in batches, returning the paths of the files neocities rejected.
+/// `state` is written after every confirmed request, so that a sync which is
+/// interrupted only has to send the remainder when it is run again.
+async fn upload_batches(
+    neocities: &Neocities,
+    path: &Path,
+    uploads: &[Upload],
+    files: &BTreeMap<String, FileState>,
+    state: &mut State,
+    state_path: &Path,
+    options: &SyncOptions,
+) -> Result<Vec<String>, SyncError> {
+    let batches = into_batches(
+        uploads.iter().collect(),
+        |upload| upload.size,
+        options.batch_size,
+        options.batch_files.max(1),
+    );
+    let mut uploads = stream::iter(batches)
+        .map(|batch| upload_batch(neocities, path, batch, options))
+        .buffer_unordered(options.concurrency.max(1));
+    let mut rejected = vec![];
+    while let Some((batch, batch_rejected)) = uploads.try_next().await? {
+        for upload in batch {
+            state.files.insert(upload.path.clone(), files[&upload.path].clone());
+        }
+        rejected.extend(batch_rejected.into_iter().map(|it| it.path.clone()));
+        write_state_file(state, state_path).await?;
+    }
+    Ok(rejected)
+}
+
+/// Differences between the local files, the state file and the site.
+#[derive(Debug, Default, Serialize)]
+pub struct Status {
+    /// Local files which are not in the state.
+    pub new: Vec<String>,
+    /// Local files whose hash differs from the state.
+    pub modified: Vec<String>,
+    /// Files in the state which no longer exist locally.
+    pub deleted: Vec<String>,
+    /// Remote files whose hash differs from the state, because they were changed
+    /// outside of neocities-sync.
+    pub changed_remotely: Vec<String>,
+    /// Files in the state which are missing from the site.
+    pub missing_remotely: Vec<String>,
+    /// Remote files which are neither local nor in the state.
+    pub remote_only: Vec<String>,
+    pub ignored: Vec<String>,
+}
+
+/// Compare the local files against the state file and the site, without changing
+/// either.
+pub async fn status(
+    neocities: &Neocities,
+    path: impl AsRef<Path>,
+    state_path: impl AsRef<Path>,
+    options: &SyncOptions,
+) -> Result<Status, SyncError> {
+    let remote = fetch_state(neocities, options.remote_dir.as_deref()).await?;
+    let current_state = match read_state_file(&state_path).await {
+        Ok(state)
+            if !options.refetch_state && state.remote_dir == options.remote_dir =>
+        {
+            state
+        }
+        Err(error)
+            if error.kind() != io::ErrorKind::NotFound && !options.refetch_state =>
+        {
+            return Err(SyncError::StateError(error));
+        }
+        _ => remote.clone(),
+    };
+    check_site(&current_state, options.username.as_deref())?;
+    let ignore_disallowed_file_types =
+        ignores_disallowed_file_types(&current_state, options);
+    let scan =
+        scan(path, state_path, &current_state, ignore_disallowed_file_types, options)
+            .await?;
+    let mut status = Status { ignored: scan.ignored, ..Default::default() };
+    for upload in plan_uploads(&scan.files, &current_state) {
+        match upload.reason {
+            UploadReason::New => status.new.push(upload.path),
+            UploadReason::Modified => status.modified.push(upload.path),
+        }
+    }
+    for (subpath, file_state) in &current_state.files {
+        if scan.filter.is_ignored(subpath, false) {
+            continue;
+        }
+        if !scan.files.contains_key(subpath) {
+            status.deleted.push(subpath.clone());
+        }
+        match remote.files.get(subpath) {
+            None => status.missing_remotely.push(subpath.clone()),
+            Some(it) if it.hash != file_state.hash => {
+                status.changed_remotely.push(subpath.clone())
+            }
+            Some(_) => {}
+        }
+    }
+    for subpath in remote.files.keys() {
+        if !current_state.files.contains_key(subpath)
+            && !scan.files.contains_key(subpath)
+            && !scan.filter.is_ignored(subpath, false)
+        {
+            status.remote_only.push(subpath.clone());
+        }
+    }
+    Ok(status)
 }
@@ -0,0 +1,524 @@
+//! An in-process fake of the neocities API, used to drive `neocities-sync` end to end
+//! without touching the real site.
+
+#![allow(dead_code)]
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+    process::Output,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Multipart, Path as AxumPath, Query, State},
+    http::{HeaderMap, StatusCode},
+    routing::{get, post},
+    Json, Router,
+};
+use base64::prelude::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+use tempfile::TempDir;
+use tokio::{net::TcpListener, process::Command};
+
+pub const USERNAME: &str = "fake";
+pub const PASSWORD: &str = "hunter2";
+pub const API_KEY: &str = "fake-api-key";
+
+#[rustfmt::skip]
+const ALLOWED_FILE_TYPES: &[&str] = &[
+    "apng", "asc", "atom", "avif", "bin", "cjs", "css", "csv", "dae", "eot", "epub",
+    "geojson", "gif", "glb", "gltf", "gpg", "htm", "html", "ico", "jpeg", "jpg", "js",
+    "json", "key", "kml", "knowl", "less", "manifest", "map", "markdown", "md", "mf",
+    "mid", "midi", "mjs", "mtl", "obj", "opml", "osdx", "otf", "pdf", "pgp", "pls",
+    "png", "py", "rdf", "resolveHandle", "rss", "sass", "scss", "svg", "text", "toml",
+    "ts", "tsv", "ttf", "txt", "webapp", "webmanifest", "webp", "woff", "woff2", "xcf",
+    "xml", "yaml", "yml"
+];
+
+const UPDATED_AT: &str = "Sat, 13 Feb 2016 03:04:00 -0000";
+
+/// A failure injected into the request to `endpoint` (any endpoint if `None`), after
+/// `skip` such requests have succeeded.
+#[derive(Debug)]
+struct Failure {
+    endpoint: Option<String>,
+    skip: usize,
+    status: StatusCode,
+    error_type: String,
+}
+
+#[derive(Debug, Default)]
+struct FakeState {
+    files: BTreeMap<String, Vec<u8>>,
+    requests: Vec<String>,
+    supporter: bool,
+    failures: Vec<Failure>,
+    /// Contents served by the public site instead of those in `files`, like a stale
+    /// cache.
+    served: BTreeMap<String, Vec<u8>>,
+    /// `updated_at` of files which were not updated at `UPDATED_AT`.
+    updated_at: BTreeMap<String, String>,
+}
+
+type Shared = Arc<Mutex<FakeState>>;
+
+type Response = (StatusCode, Json<Value>);
+
+fn success(mut body: Value) -> Response {
+    body["result"] = json!("success");
+    (StatusCode::OK, Json(body))
+}
+
+fn error(status: StatusCode, error_type: &str, message: &str) -> Response {
+    (
+        status,
+        Json(
+            json!({ "result": "error", "error_type": error_type, "message": message }),
+        ),
+    )
+}
+
+fn invalid_auth() -> Response {
+    error(
+        StatusCode::FORBIDDEN,
+        "invalid_auth",
+        "invalid credentials - please check your username and password (or your api key)",
+    )
+}
+
+fn is_authorized(headers: &HeaderMap) -> bool {
+    let Some(authorization) =
+        headers.get("Authorization").and_then(|it| it.to_str().ok())
+    else {
+        return false;
+    };
+    if let Some(api_key) = authorization.strip_prefix("Bearer ") {
+        return api_key == API_KEY;
+    }
+    if let Some(credentials) = authorization.strip_prefix("Basic ") {
+        let expected = BASE64_STANDARD.encode(format!("{}:{}", USERNAME, PASSWORD));
+        return credentials == expected;
+    }
+    false
+}
+
+fn sha1_hash(contents: &[u8]) -> String {
+    format!("{:x}", Sha1::digest(contents))
+}
+
+fn is_of_allowed_file_type(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|it| ALLOWED_FILE_TYPES.contains(&it.to_str().unwrap()))
+}
+
+/// Record a request to `endpoint`, returning the injected failure for it if any.
+fn record(state: &Shared, endpoint: &str) -> Option<Response> {
+    let state = &mut *state.lock().unwrap();
+    state.requests.push(endpoint.to_string());
+    let index = state
+        .failures
+        .iter()
+        .position(|it| it.endpoint.as_deref().is_none_or(|it| it == endpoint))?;
+    if state.failures[index].skip > 0 {
+        state.failures[index].skip -= 1;
+        return None;
+    }
+    let failure = state.failures.remove(index);
+    let message = format!("injected {} failure", failure.error_type);
+    Some(error(failure.status, &failure.error_type, &message))
+}
+
+async fn key(State(state): State<Shared>, headers: HeaderMap) -> Response {
+    if let Some(response) = record(&state, "key") {
+        return response;
+    }
+    if !is_authorized(&headers) {
+        return invalid_auth();
+    }
+    success(json!({ "api_key": API_KEY }))
+}
+
+async fn upload(
+    State(state): State<Shared>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    if let Some(response) = record(&state, "upload") {
+        return response;
+    }
+    if !is_authorized(&headers) {
+        return invalid_auth();
+    }
+    let mut files = vec![];
+    while let Ok(Some(field)) = multipart.next_field().await {
+        // Paths containing `/` are sent as `name*=utf-8''...`, which multer does not
+        // parse, so fall back to the file name like neocities does.
+        let Some(name) = field.name().or(field.file_name()).map(str::to_string) else {
+            continue;
+        };
+        let Ok(contents) = field.bytes().await else {
+            return error(StatusCode::BAD_REQUEST, "invalid_request", "bad upload");
+        };
+        files.push((name.trim_start_matches('/').to_string(), contents.to_vec()));
+    }
+    if files.is_empty() {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "missing_files",
+            "you must provide files to upload",
+        );
+    }
+    let state = &mut *state.lock().unwrap();
+    for (name, _) in &files {
+        if !state.supporter && !is_of_allowed_file_type(name) {
+            return error(
+                StatusCode::BAD_REQUEST,
+                "invalid_file_type",
+                &format!(
+                    "{} is not an allowed file type for free sites, supporter required",
+                    name
+                ),
+            );
+        }
+    }
+    for (name, contents) in files {
+        state.files.insert(name, contents);
+    }
+    success(json!({ "message": "your file(s) have been successfully uploaded" }))
+}
+
+async fn delete(
+    State(state): State<Shared>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    if let Some(response) = record(&state, "delete") {
+        return response;
+    }
+    if !is_authorized(&headers) {
+        return invalid_auth();
+    }
+    let mut filenames = vec![];
+    while let Ok(Some(field)) = multipart.next_field().await {
+        if field.name() != Some("filenames[]") {
+            continue;
+        }
+        let Ok(filename) = field.text().await else {
+            return error(StatusCode::BAD_REQUEST, "invalid_request", "bad delete");
+        };
+        filenames.push(filename.trim_start_matches('/').to_string());
+    }
+    let state = &mut *state.lock().unwrap();
+    for filename in &filenames {
+        if filename == "index.html" {
+            return error(
+                StatusCode::BAD_REQUEST,
+                "cannot_delete_index",
+                "you cannot delete your index.html file, canceled deleting",
+            );
+        }
+        let prefix = format!("{}/", filename);
+        if !state.files.keys().any(|it| it == filename || it.starts_with(&prefix)) {
+            return error(
+                StatusCode::BAD_REQUEST,
+                "missing_files",
+                &format!("{} was not found on your site, canceled deleting", filename),
+            );
+        }
+    }
+    for filename in filenames {
+        let prefix = format!("{}/", filename);
+        state.files.retain(|it, _| *it != filename && !it.starts_with(&prefix));
+    }
+    success(json!({ "message": "file(s) have been deleted" }))
+}
+
+async fn rename(
+    State(state): State<Shared>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    if let Some(response) = record(&state, "rename") {
+        return response;
+    }
+    if !is_authorized(&headers) {
+        return invalid_auth();
+    }
+    let mut fields = BTreeMap::new();
+    while let Ok(Some(field)) = multipart.next_field().await {
+        let Some(name) = field.name().map(str::to_string) else {
+            continue;
+        };
+        let Ok(value) = field.text().await else {
+            return error(StatusCode::BAD_REQUEST, "invalid_request", "bad rename");
+        };
+        fields.insert(name, value.trim_start_matches('/').to_string());
+    }
+    let (Some(path), Some(new_path)) = (fields.get("path"), fields.get("new_path"))
+    else {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "missing_arguments",
+            "you must provide path and new_path",
+        );
+    };
+    let state = &mut *state.lock().unwrap();
+    let Some(contents) = state.files.remove(path) else {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "missing_file",
+            &format!("{} was not found on your site", path),
+        );
+    };
+    if state.files.contains_key(new_path) {
+        state.files.insert(path.clone(), contents);
+        return error(
+            StatusCode::BAD_REQUEST,
+            "rename_error",
+            &format!("{} already exists", new_path),
+        );
+    }
+    state.files.insert(new_path.clone(), contents);
+    success(json!({ "message": format!("{} has been renamed to {}", path, new_path) }))
+}
+
+#[derive(Debug, Deserialize)]
+struct ListQuery {
+    path: Option<String>,
+}
+
+async fn list(
+    State(state): State<Shared>,
+    headers: HeaderMap,
+    Query(query): Query<ListQuery>,
+) -> Response {
+    if let Some(response) = record(&state, "list") {
+        return response;
+    }
+    if !is_authorized(&headers) {
+        return invalid_auth();
+    }
+    let state = state.lock().unwrap();
+    let prefix = query
+        .path
+        .map(|it| it.trim_matches('/').to_string())
+        .filter(|it| !it.is_empty())
+        .map(|it| format!("{}/", it));
+    let is_listed = |path: &str| prefix.as_ref().is_none_or(|it| path.starts_with(it));
+    let mut directories = BTreeSet::new();
+    let mut files = vec![];
+    for (path, contents) in &state.files {
+        for directory in Path::new(path).ancestors().skip(1) {
+            let directory = directory.to_str().unwrap();
+            if !directory.is_empty() && is_listed(directory) {
+                directories.insert(directory.to_string());
+            }
+        }
+        if is_listed(path) {
+            files.push(json!({
+                "path": path,
+                "is_directory": false,
+                "size": contents.len(),
+                "updated_at": state.updated_at.get(path).map_or(UPDATED_AT, |it| it),
+                "sha1_hash": sha1_hash(contents),
+            }));
+        }
+    }
+    for directory in directories {
+        files.push(json!({
+            "path": directory,
+            "is_directory": true,
+            "updated_at": UPDATED_AT,
+        }));
+    }
+    success(json!({ "files": files }))
+}
+
+#[derive(Debug, Deserialize)]
+struct InfoQuery {
+    sitename: Option<String>,
+}
+
+async fn info(
+    State(state): State<Shared>,
+    headers: HeaderMap,
+    Query(query): Query<InfoQuery>,
+) -> Response {
+    if let Some(response) = record(&state, "info") {
+        return response;
+    }
+    match query.sitename.as_deref() {
+        Some(USERNAME) => {}
+        Some(_) => {
+            return error(StatusCode::NOT_FOUND, "site_not_found", "site not found")
+        }
+        None if !is_authorized(&headers) => return invalid_auth(),
+        None => {}
+    }
+    success(json!({
+        "info": {
+            "sitename": USERNAME,
+            "views": 1337,
+            "hits": 4242,
+            "created_at": "Sat, 29 Jun 2013 10:11:38 +0000",
+            "last_updated": UPDATED_AT,
+            "domain": null,
+            "tags": ["fake", "testing"],
+            "latest_ipfs_hash": null,
+        }
+    }))
+}
+
+/// The public site, at `/site/{path}`.
+async fn site(
+    State(state): State<Shared>,
+    AxumPath(path): AxumPath<String>,
+) -> (StatusCode, Vec<u8>) {
+    let state = &mut *state.lock().unwrap();
+    state.requests.push("site".to_string());
+    match state.served.get(&path).or_else(|| state.files.get(&path)) {
+        Some(contents) => (StatusCode::OK, contents.clone()),
+        None => (StatusCode::NOT_FOUND, b"not found".to_vec()),
+    }
+}
+
+/// A fake neocities server listening on a random local port.
+pub struct FakeNeocities {
+    pub url: String,
+    state: Shared,
+}
+
+impl FakeNeocities {
+    pub async fn start() -> Self {
+        let state = Shared::default();
+        let app = Router::new()
+            .route("/api/key", get(key))
+            .route("/api/upload", post(upload))
+            .route("/api/delete", post(delete))
+            .route("/api/rename", post(rename))
+            .route("/api/list", get(list))
+            .route("/api/info", get(info))
+            .route("/site/*path", get(site))
+            .with_state(state.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        Self { url, state }
+    }
+
+    pub fn set_supporter(&self, supporter: bool) {
+        self.state.lock().unwrap().supporter = supporter;
+    }
+
+    /// Make the next request fail with `error_type`.
+    pub fn fail_next(&self, status: StatusCode, error_type: &str) {
+        self.state.lock().unwrap().failures.push(Failure {
+            endpoint: None,
+            skip: 0,
+            status,
+            error_type: error_type.to_string(),
+        });
+    }
+
+    /// Make a request to `/api/{endpoint}` fail with `error_type`, after `skip` of them
+    /// have succeeded.
+    pub fn fail(
+        &self,
+        endpoint: &str,
+        skip: usize,
+        status: StatusCode,
+        error_type: &str,
+    ) {
+        self.state.lock().unwrap().failures.push(Failure {
+            endpoint: Some(endpoint.to_string()),
+            skip,
+            status,
+            error_type: error_type.to_string(),
+        });
+    }
+
+    pub fn put(&self, path: &str, contents: impl Into<Vec<u8>>) {
+        self.state.lock().unwrap().files.insert(path.to_string(), contents.into());
+    }
+
+    /// Set when `path` was last updated, as reported by `/api/list`.
+    pub fn set_updated_at(&self, path: &str, updated_at: &str) {
+        let state = &mut *self.state.lock().unwrap();
+        state.updated_at.insert(path.to_string(), updated_at.to_string());
+    }
+
+    /// Make the public site serve `contents` for `path`.
+    pub fn serve(&self, path: &str, contents: impl Into<Vec<u8>>) {
+        self.state.lock().unwrap().served.insert(path.to_string(), contents.into());
+    }
+
+    pub fn remove(&self, path: &str) {
+        self.state.lock().unwrap().files.remove(path);
+    }
+
+    pub fn get(&self, path: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().files.get(path).cloned()
+    }
+
+    pub fn paths(&self) -> Vec<String> {
+        self.state.lock().unwrap().files.keys().cloned().collect()
+    }
+
+    /// Number of requests made to `/api/{endpoint}` so far.
+    pub fn requests(&self, endpoint: &str) -> usize {
+        self.state.lock().unwrap().requests.iter().filter(|it| *it == endpoint).count()
+    }
+
+    /// Run `neocities-sync` with `args`, authenticated against this server.
+    pub async fn run(&self, args: &[&str]) -> Output {
+        self.run_with_key(API_KEY, args).await
+    }
+
+    pub async fn run_with_key(&self, api_key: &str, args: &[&str]) -> Output {
+        let config_dir = std::env::temp_dir().join("neocities-sync-tests");
+        Command::new(env!("CARGO_BIN_EXE_neocities-sync"))
+            .args(args)
+            .env("NEOCITIES_API_URL", &self.url)
+            .env("NEOCITIES_API_KEY", api_key)
+            .env("XDG_CONFIG_HOME", config_dir)
+            .env("NO_COLOR", "1")
+            .output()
+            .await
+            .unwrap()
+    }
+}
+
+/// A supporter site with `index.html`, `css/style.css` and `img/cat.png`.
+pub async fn setup() -> (FakeNeocities, TempDir) {
+    let server = FakeNeocities::start().await;
+    server.set_supporter(true);
+    let site = TempDir::new().unwrap();
+    write(site.path(), "index.html", "<h1>hello</h1>");
+    write(site.path(), "css/style.css", "h1 { color: red; }");
+    write(site.path(), "img/cat.png", [0x89, b'P', b'N', b'G']);
+    (server, site)
+}
+
+/// Write `contents` to `path` relative to `root`, creating parent directories.
+pub fn write(root: &Path, path: &str, contents: impl AsRef<[u8]>) {
+    let path = root.join(path);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, contents).unwrap();
+}
+
+/// The `.state` file in `site`, as JSON.
+pub fn read_state(site: &Path) -> serde_json::Value {
+    serde_json::from_slice(&std::fs::read(site.join(".state")).unwrap()).unwrap()
+}
+
+pub fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
@@ -0,0 +1,154 @@
+mod common;
+
+use common::{read_state, setup, stderr, stdout, write};
+
+#[tokio::test]
+async fn upload_maps_a_directory_to_a_remote_path() {
+    let (server, site) = setup().await;
+    let css = site.path().join("css");
+    let output =
+        server.run(&["upload", css.to_str().unwrap(), "/assets/", "-u", "fake"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "[uploaded] assets/style.css\n");
+    assert_eq!(server.paths(), ["assets/style.css"]);
+    let index = site.path().join("index.html");
+    let output = server.run(&["upload", index.to_str().unwrap(), "-u", "fake"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(server.get("index.html").unwrap(), b"<h1>hello</h1>");
+}
+
+#[tokio::test]
+async fn upload_updates_the_state_file() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    let output = server.run(&["sync", path, "--username", "fake"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    write(site.path(), "css/style.css", "h1 { color: blue; }");
+    let style = site.path().join("css/style.css");
+    let state = site.path().join(".state");
+    let output = server
+        .run(&[
+            "upload",
+            style.to_str().unwrap(),
+            "css/style.css",
+            "--username",
+            "fake",
+            "--state",
+            state.to_str().unwrap(),
+        ])
+        .await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(server.get("css/style.css").unwrap(), b"h1 { color: blue; }");
+    assert_eq!(
+        read_state(site.path())["files"]["css/style.css"]["hash"],
+        "c1aa7f02c9f776823affbcb863f2b361db3278ce"
+    );
+    let output = server.run(&["sync", path, "--username", "fake"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 0, deleted 0"));
+    assert_eq!(server.requests("upload"), 2);
+}
+
+#[tokio::test]
+async fn rm_updates_the_state_file() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    let output = server.run(&["sync", path, "--username", "fake"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    let state = site.path().join(".state");
+    let output =
+        server.run(&["rm", "img/", "-u", "fake", "-s", state.to_str().unwrap()]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "[deleted] img\n");
+    assert!(server.get("img/cat.png").is_none());
+    assert!(read_state(site.path())["files"].get("img/cat.png").is_none());
+    let output = server.run(&["sync", path, "--username", "fake"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 1, deleted 0"));
+}
+
+#[tokio::test]
+async fn rm_reports_missing_files() {
+    let (server, _site) = setup().await;
+    server.put("index.html", "<h1>hello</h1>");
+    let output = server.run(&["rm", "nope.html", "-u", "fake"]).await;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("File not found:"));
+    let output = server.run(&["rm", "index.html", "-u", "fake"]).await;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("does not allow deleting index.html"));
+    assert_eq!(server.requests("delete"), 1);
+}
+
+#[tokio::test]
+async fn upload_reports_missing_files() {
+    let (server, site) = setup().await;
+    let missing = site.path().join("missing.html");
+    let output = server.run(&["upload", missing.to_str().unwrap(), "-u", "fake"]).await;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Cannot read"));
+    assert!(!stderr(&output).contains("Panic!"));
+    assert_eq!(server.requests("upload"), 0);
+}
+
+#[tokio::test]
+async fn upload_respects_the_remote_dir_of_the_state() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    let output =
+        server.run(&["sync", path, "-u", "fake", "--remote-dir", "docs"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    write(site.path(), "css/style.css", "h1 { color: blue; }");
+    let style = site.path().join("css/style.css");
+    let state = site.path().join(".state");
+    for remote in ["docs/css/style.css", "style.css"] {
+        let args = [
+            "upload",
+            style.to_str().unwrap(),
+            remote,
+            "-u",
+            "fake",
+            "-s",
+            state.to_str().unwrap(),
+        ];
+        let output = server.run(&args).await;
+        assert!(output.status.success(), "{}", stderr(&output));
+    }
+    let files = read_state(site.path())["files"].clone();
+    assert_eq!(
+        files["css/style.css"]["hash"],
+        "c1aa7f02c9f776823affbcb863f2b361db3278ce"
+    );
+    assert!(files.get("docs/css/style.css").is_none());
+    assert!(files.get("style.css").is_none());
+    let output =
+        server.run(&["sync", path, "-u", "fake", "--remote-dir", "docs"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 0, deleted 0, renamed 0"));
+    assert_eq!(server.requests("delete"), 0);
+}
+
+#[tokio::test]
+async fn rm_respects_the_remote_dir_of_the_state() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    let output =
+        server.run(&["sync", path, "-u", "fake", "--remote-dir", "docs"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    let state = site.path().join(".state");
+    let output = server
+        .run(&["rm", "docs/img", "-u", "fake", "-s", state.to_str().unwrap()])
+        .await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    let files = read_state(site.path())["files"].clone();
+    assert!(files.get("img/cat.png").is_none());
+    assert!(files.get("css/style.css").is_some());
+    let output =
+        server.run(&["sync", path, "-u", "fake", "--remote-dir", "docs"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 1, deleted 0"));
+    let output =
+        server.run(&["rm", "docs", "-u", "fake", "-s", state.to_str().unwrap()]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(read_state(site.path())["files"].as_object().unwrap().is_empty());
+}
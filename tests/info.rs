@@ -0,0 +1,48 @@
+mod common;
+
+use common::{stderr, stdout, FakeNeocities};
+
+#[tokio::test]
+async fn info_shows_logged_in_site() {
+    let server = FakeNeocities::start().await;
+    let output = server.run(&["info"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = stdout(&output);
+    assert!(stdout.contains("sitename   fake\n"));
+    assert!(stdout.contains("hits       4242\n"));
+    assert!(stdout.contains("views      1337\n"));
+    assert!(stdout.contains("created    Sat, 29 Jun 2013 10:11:38 +0000\n"));
+    assert!(stdout.contains("domain     -\n"));
+    assert!(stdout.contains("tags       fake, testing\n"));
+}
+
+#[tokio::test]
+async fn info_of_other_sites_does_not_need_login() {
+    let server = FakeNeocities::start().await;
+    let output = server.run_with_key("wrong-key", &["info", "fake"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("sitename   fake\n"));
+    let output = server.run_with_key("wrong-key", &["info"]).await;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Invalid session."));
+}
+
+#[tokio::test]
+async fn info_of_unknown_site_is_reported() {
+    let server = FakeNeocities::start().await;
+    let output = server.run(&["info", "nobody"]).await;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Site not found."));
+}
+
+#[tokio::test]
+async fn info_as_json() {
+    let server = FakeNeocities::start().await;
+    let output = server.run(&["info", "fake", "--json"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    let info: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    assert_eq!(info["sitename"], "fake");
+    assert_eq!(info["hits"], 4242);
+    assert_eq!(info["domain"], serde_json::Value::Null);
+    assert_eq!(info["tags"], serde_json::json!(["fake", "testing"]));
+}
@@ -0,0 +1,78 @@
+mod common;
+
+use common::{stderr, stdout, FakeNeocities};
+
+async fn setup() -> FakeNeocities {
+    let server = FakeNeocities::start().await;
+    server.put("index.html", "<h1>hello</h1>");
+    server.put("css/style.css", "h1 { color: red; }");
+    server.put("css/fonts/serif.woff2", "wOF2");
+    server.put("img/cat.png", "PNG");
+    server
+}
+
+#[tokio::test]
+async fn ls_lists_the_root_directory() {
+    let server = setup().await;
+    let output = server.run(&["ls"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "css/\nimg/\nindex.html\n");
+}
+
+#[tokio::test]
+async fn ls_lists_a_directory() {
+    let server = setup().await;
+    let output = server.run(&["ls", "css"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "css/fonts/\ncss/style.css\n");
+    let output = server.run(&["ls", "/css/", "--recursive"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "css/fonts/\ncss/fonts/serif.woff2\ncss/style.css\n");
+}
+
+#[tokio::test]
+async fn ls_long_listing() {
+    let server = setup().await;
+    let output = server.run(&["ls", "--long"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = stdout(&output);
+    let lines = stdout.lines().collect::<Vec<_>>();
+    assert_eq!(
+        lines[2],
+        "      14 B  Sat, 13 Feb 2016 03:04:00 -0000  \
+         8fef0f6104ab3c32e8f6a5766994f057dd1fa6b4  index.html"
+    );
+    assert!(lines[0].starts_with("         -  Sat, 13 Feb 2016 03:04:00 -0000  -"));
+    assert!(lines[0].ends_with("  css/"));
+}
+
+#[tokio::test]
+async fn ls_sorts_by_size_and_time() {
+    let server = setup().await;
+    server.set_updated_at("css/style.css", "Mon, 01 Jan 2024 10:00:00 +0200");
+    server.set_updated_at("index.html", "Mon, 01 Jan 2024 09:00:00 -0000");
+    let output = server.run(&["ls", "-R", "--sort", "size"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    let listing = stdout(&output);
+    let files = listing.lines().filter(|it| !it.ends_with('/')).collect::<Vec<_>>();
+    assert_eq!(
+        files,
+        ["css/style.css", "index.html", "css/fonts/serif.woff2", "img/cat.png"]
+    );
+    let output = server.run(&["ls", "-R", "--sort", "time", "--reverse"]).await;
+    let listing = stdout(&output);
+    let lines = listing.lines().collect::<Vec<_>>();
+    assert_eq!(lines[lines.len() - 2..], ["css/style.css", "index.html"]);
+}
+
+#[tokio::test]
+async fn ls_as_json() {
+    let server = setup().await;
+    let output = server.run(&["ls", "img", "--json"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    let entries: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    assert_eq!(entries[0]["path"], "img/cat.png");
+    assert_eq!(entries[0]["is_directory"], false);
+    assert_eq!(entries[0]["size"], 3);
+    assert_eq!(entries[0]["updated_at"], "Sat, 13 Feb 2016 03:04:00 -0000");
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use common::{stderr, stdout, write, FakeNeocities};
+use tempfile::TempDir;
+
+async fn setup() -> (FakeNeocities, TempDir) {
+    let server = FakeNeocities::start().await;
+    server.put("index.html", "<h1>hello</h1>");
+    server.put("css/style.css", "h1 { color: red; }");
+    (server, TempDir::new().unwrap())
+}
+
+async fn pull(server: &FakeNeocities, site: &TempDir) -> std::process::Output {
+    let site_url = format!("{}/site", server.url);
+    let path = site.path().to_str().unwrap();
+    server.run(&["pull", path, "--username", "fake", "--site-url", &site_url]).await
+}
+
+#[tokio::test]
+async fn pull_downloads_the_site() {
+    let (server, site) = setup().await;
+    let output = pull(&server, &site).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "[downloaded] css/style.css\n[downloaded] index.html\n"
+    );
+    assert!(stderr(&output).contains("downloaded 2, unchanged 0"));
+    let style = std::fs::read(site.path().join("css/style.css")).unwrap();
+    assert_eq!(style, b"h1 { color: red; }");
+    let path = site.path().to_str().unwrap();
+    let output = server.run(&["sync", path, "--username", "fake"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 0, deleted 0"));
+    assert_eq!(server.requests("list"), 1);
+}
+
+#[tokio::test]
+async fn pull_skips_files_which_already_match() {
+    let (server, site) = setup().await;
+    write(site.path(), "index.html", "<h1>hello</h1>");
+    write(site.path(), "css/style.css", "h1 { color: blue; }");
+    let output = pull(&server, &site).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("downloaded 1, unchanged 1"));
+    assert_eq!(server.requests("site"), 1);
+    let style = std::fs::read(site.path().join("css/style.css")).unwrap();
+    assert_eq!(style, b"h1 { color: red; }");
+}
+
+#[tokio::test]
+async fn pull_rejects_downloads_with_wrong_hash() {
+    let (server, site) = setup().await;
+    server.serve("index.html", "<h1>stale</h1>");
+    let output = pull(&server, &site).await;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Download does not match neocities:"));
+    assert!(!site.path().join("index.html").exists());
+    assert!(!site.path().join("index.html.tmp").exists());
+    assert!(!site.path().join(".state").exists());
+}
+
+#[tokio::test]
+async fn pull_encodes_file_names() {
+    let (server, site) = setup().await;
+    server.put("notes #1.txt", "first");
+    let output = pull(&server, &site).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    let notes = std::fs::read(site.path().join("notes #1.txt")).unwrap();
+    assert_eq!(notes, b"first");
+}
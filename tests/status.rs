@@ -0,0 +1,59 @@
+mod common;
+
+use common::{setup, stderr, stdout, write};
+
+#[tokio::test]
+async fn status_of_synced_site_is_up_to_date() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    server.run(&["sync", path]).await;
+    let output = server.run(&["status", path]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "");
+    assert!(stderr(&output).contains("Up to date."));
+}
+
+#[tokio::test]
+async fn status_reports_local_and_remote_drift() {
+    let (server, site) = setup().await;
+    write(site.path(), "about.html", "<p>about</p>");
+    let path = site.path().to_str().unwrap();
+    server.run(&["sync", path]).await;
+    write(site.path(), "css/style.css", "h1 { color: blue; }");
+    write(site.path(), "new.html", "<p>new</p>");
+    std::fs::remove_file(site.path().join("img/cat.png")).unwrap();
+    server.put("index.html", "<h1>defaced</h1>");
+    server.remove("about.html");
+    server.put("extra.html", "<p>extra</p>");
+    let output = server.run(&["status", path]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "[new] new.html\n\
+         [modified] css/style.css\n\
+         [deleted] img/cat.png\n\
+         [changed remotely] index.html\n\
+         [missing remotely] about.html\n\
+         [remote only] extra.html\n"
+    );
+    assert!(stderr(&output).contains(
+        "1 new, 1 modified, 1 deleted, 1 changed remotely, 1 missing remotely, 1 \
+         remote only"
+    ));
+    assert_eq!(server.requests("upload"), 1);
+    assert_eq!(server.requests("delete"), 0);
+}
+
+#[tokio::test]
+async fn status_without_state_file_compares_against_remote() {
+    let (server, site) = setup().await;
+    server.put("index.html", "<h1>hello</h1>");
+    let output =
+        server.run(&["status", site.path().to_str().unwrap(), "-o", "json"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    let status: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    assert_eq!(status["new"], serde_json::json!(["css/style.css", "img/cat.png"]));
+    assert_eq!(status["modified"], serde_json::json!([]));
+    assert_eq!(status["remote_only"], serde_json::json!([]));
+    assert!(!site.path().join(".state").exists());
+}
@@ -0,0 +1,788 @@
+mod common;
+
+use axum::http::StatusCode;
+use common::{read_state, setup, stderr, stdout, write, FakeNeocities};
+use tempfile::TempDir;
+
+#[tokio::test]
+async fn first_sync_uploads_everything() {
+    let (server, site) = setup().await;
+    let output = server.run(&["sync", site.path().to_str().unwrap()]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 3, deleted 0"));
+    assert_eq!(server.paths(), ["css/style.css", "img/cat.png", "index.html"]);
+    assert_eq!(server.get("index.html").unwrap(), b"<h1>hello</h1>");
+    assert!(site.path().join(".state").exists());
+}
+
+#[tokio::test]
+async fn state_file_records_file_metadata() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    let output = server.run(&["sync", path, "--username", "fake"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    let state = read_state(site.path());
+    assert_eq!(state["version"], 2);
+    assert_eq!(state["username"], "fake");
+    assert!(state["last_sync"].is_u64());
+    let files = state["files"].as_object().unwrap();
+    assert_eq!(
+        files.keys().collect::<Vec<_>>(),
+        ["css/style.css", "img/cat.png", "index.html"]
+    );
+    let style = &files["css/style.css"];
+    assert_eq!(style["hash"], "ab19144fbc1a47311ac081826a55393f81857737");
+    assert_eq!(style["size"], 18);
+    assert!(style["mtime"].is_u64());
+    assert!(style["uploaded_at"].is_u64());
+}
+
+#[tokio::test]
+async fn state_file_of_another_site_is_refused() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    server.run(&["sync", path]).await;
+    let mut state = read_state(site.path());
+    state["username"] = "other".into();
+    write(site.path(), ".state", state.to_string());
+    server.remove("css/style.css");
+    let output = server.run(&["sync", path]).await;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("State file belongs to another site."));
+    assert_eq!(server.requests("upload"), 1);
+    let output = server.run(&["sync", path, "--refetch-state"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 1, deleted 0"));
+    assert_eq!(read_state(site.path())["username"], "fake");
+}
+
+#[tokio::test]
+async fn state_file_records_the_site_of_the_api_key() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    let output = server.run(&["sync", path, "--username", "someone-else"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(read_state(site.path())["username"], "fake");
+    assert_eq!(server.requests("info"), 1);
+}
+
+#[tokio::test]
+async fn legacy_state_file_is_migrated() {
+    let (server, site) = setup().await;
+    server.put("css/style.css", "h1 { color: red; }");
+    server.put("old.html", "stale");
+    write(
+        site.path(),
+        ".state",
+        "old.html:0000000000000000000000000000000000000000\n\
+         css/style.css:ab19144fbc1a47311ac081826a55393f81857737\n",
+    );
+    let output = server.run(&["sync", site.path().to_str().unwrap()]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 2, deleted 1"));
+    assert_eq!(server.requests("list"), 0);
+    let state = read_state(site.path());
+    assert_eq!(state["version"], 2);
+    assert!(state["files"].get("old.html").is_none());
+    assert_eq!(state["files"]["css/style.css"]["size"], 18);
+}
+
+#[tokio::test]
+async fn newer_state_file_version_is_refused() {
+    let (server, site) = setup().await;
+    write(site.path(), ".state", r#"{"version": 3, "files": {}}"#);
+    let output = server.run(&["sync", site.path().to_str().unwrap()]).await;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("state file version 3 is newer"));
+    assert_eq!(server.requests("upload"), 0);
+}
+
+#[tokio::test]
+async fn second_sync_without_changes_uploads_nothing() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    server.run(&["sync", path]).await;
+    let output = server.run(&["sync", path]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 0, deleted 0"));
+    assert_eq!(server.requests("upload"), 1);
+    assert_eq!(server.requests("delete"), 0);
+}
+
+#[tokio::test]
+async fn unchanged_size_and_mtime_skip_hashing() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    server.run(&["sync", path]).await;
+    // Same size and mtime, so only `--rehash` notices the change.
+    let file = site.path().join("css/style.css");
+    let mtime = std::fs::metadata(&file).unwrap().modified().unwrap();
+    write(site.path(), "css/style.css", "h1 { color: tan; }");
+    std::fs::File::options()
+        .write(true)
+        .open(&file)
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+    let output = server.run(&["sync", path]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 0, deleted 0"));
+    let output = server.run(&["sync", path, "--rehash"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 1, deleted 0"));
+    assert_eq!(server.get("css/style.css").unwrap(), b"h1 { color: tan; }");
+}
+
+#[tokio::test]
+async fn modified_and_removed_files_are_synced() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    server.run(&["sync", path]).await;
+    write(site.path(), "css/style.css", "h1 { color: blue; }");
+    std::fs::remove_file(site.path().join("img/cat.png")).unwrap();
+    let output = server.run(&["sync", path]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 1, deleted 1"));
+    assert_eq!(server.paths(), ["css/style.css", "index.html"]);
+    assert_eq!(server.get("css/style.css").unwrap(), b"h1 { color: blue; }");
+}
+
+#[tokio::test]
+async fn missing_state_is_fetched_from_remote() {
+    let (server, site) = setup().await;
+    server.put("index.html", "<h1>hello</h1>");
+    server.put("old.html", "stale");
+    let output = server.run(&["sync", site.path().to_str().unwrap()]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 2, deleted 1"));
+    assert_eq!(server.requests("list"), 1);
+    assert_eq!(server.paths(), ["css/style.css", "img/cat.png", "index.html"]);
+}
+
+#[tokio::test]
+async fn state_file_outside_of_directory() {
+    let (server, site) = setup().await;
+    let state = TempDir::new().unwrap();
+    let state = state.path().join("state");
+    let args =
+        ["sync", site.path().to_str().unwrap(), "--state", state.to_str().unwrap()];
+    let output = server.run(&args).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(state.exists());
+    assert!(!site.path().join(".state").exists());
+}
+
+#[tokio::test]
+async fn invalid_api_key_is_reported() {
+    let (server, site) = setup().await;
+    let output =
+        server.run_with_key("wrong", &["sync", site.path().to_str().unwrap()]).await;
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Invalid session."));
+    assert!(server.paths().is_empty());
+}
+
+#[tokio::test]
+async fn disallowed_file_types_are_rejected_for_free_sites() {
+    let (server, site) = setup().await;
+    server.set_supporter(false);
+    write(site.path(), "archive.zip", "PK");
+    write(site.path(), "bin/tool.exe", "MZ");
+    let path = site.path().to_str().unwrap();
+    let output = server.run(&["sync", path, "--all-file-types"]).await;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("[rejected] archive.zip"));
+    assert!(stderr(&output).contains("[rejected] bin/tool.exe"));
+    assert!(stderr(&output).contains("uploaded 3, deleted 0"));
+    assert!(stderr(&output).contains("Invalid file type."));
+    assert_eq!(server.paths(), ["css/style.css", "img/cat.png", "index.html"]);
+    let state = read_state(site.path());
+    assert!(state["files"].get("archive.zip").is_none());
+    let output = server.run(&["sync", path, "--all-file-types"]).await;
+    assert!(stderr(&output).contains("uploaded 0, deleted 0"));
+    assert!(stderr(&output).contains("[rejected] archive.zip"));
+}
+
+#[tokio::test]
+async fn disallowed_file_types_are_ignored_once_rejected() {
+    let (server, site) = setup().await;
+    server.set_supporter(false);
+    write(site.path(), "archive.zip", "PK");
+    let path = site.path().to_str().unwrap();
+    let output = server.run(&["sync", path]).await;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("[rejected] archive.zip"));
+    assert_eq!(read_state(site.path())["supporter"], false);
+    let output = server.run(&["sync", path]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("[ignored] archive.zip"));
+    assert!(stderr(&output).contains("uploaded 0, deleted 0"));
+    assert_eq!(server.paths(), ["css/style.css", "img/cat.png", "index.html"]);
+}
+
+#[tokio::test]
+async fn supporter_sites_upload_every_file_type() {
+    let (server, site) = setup().await;
+    write(site.path(), "archive.zip", "PK");
+    let output = server.run(&["sync", site.path().to_str().unwrap()]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 4, deleted 0"));
+    assert_eq!(read_state(site.path())["supporter"], true);
+}
+
+#[tokio::test]
+async fn disallowed_file_types_can_be_ignored() {
+    let (server, site) = setup().await;
+    server.set_supporter(false);
+    write(site.path(), "archive.zip", "PK");
+    let path = site.path().to_str().unwrap();
+    let output = server.run(&["sync", path, "--ignore-disallowed-file-types"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("[ignored]"));
+    assert_eq!(server.paths(), ["css/style.css", "img/cat.png", "index.html"]);
+}
+
+#[tokio::test]
+async fn missing_files_after_reconciling_is_reported() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    write(site.path(), "about.html", "about");
+    server.run(&["sync", path]).await;
+    std::fs::remove_file(site.path().join("about.html")).unwrap();
+    server.fail("delete", 0, StatusCode::BAD_REQUEST, "missing_files");
+    server.fail("delete", 0, StatusCode::BAD_REQUEST, "missing_files");
+    let output = server.run(&["sync", path]).await;
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Out of sync."));
+    assert!(stderr(&output).contains("--refetch-state"));
+    assert_eq!(server.requests("delete"), 2);
+}
+
+#[tokio::test]
+async fn rejected_files_are_not_sent_again_when_reconciling() {
+    let (server, site) = setup().await;
+    server.set_supporter(false);
+    let path = site.path().to_str().unwrap();
+    write(site.path(), "old.html", "old");
+    server.run(&["sync", path, "--all-file-types"]).await;
+    std::fs::remove_file(site.path().join("old.html")).unwrap();
+    write(site.path(), "archive.zip", "PK");
+    server.remove("old.html");
+    let output = server.run(&["sync", path, "--all-file-types"]).await;
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stderr(&output).matches("[rejected] archive.zip").count(), 1);
+    assert!(stderr(&output).contains("Neocities rejected 1 files."));
+    assert_eq!(server.requests("upload"), 2);
+}
+
+#[tokio::test]
+async fn unknown_api_errors_are_reported() {
+    let (server, site) = setup().await;
+    server.put("index.html", "<h1>hello</h1>");
+    server.fail_next(StatusCode::BAD_REQUEST, "too_large");
+    let output = server.run(&["sync", site.path().to_str().unwrap()]).await;
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = stderr(&output);
+    assert!(stderr.contains("too_large (400 Bad Request): injected too_large failure"));
+    assert!(!stderr.contains("Panic!"));
+}
+
+#[tokio::test]
+async fn dry_run_prints_plan_without_touching_the_site() {
+    let (server, site) = setup().await;
+    server.put("index.html", "<h1>old</h1>");
+    server.put("old.html", "stale");
+    let path = site.path().to_str().unwrap();
+    let output = server.run(&["sync", path, "--dry-run"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "[new] css/style.css (18 B)\n\
+         [new] img/cat.png (4 B)\n\
+         [modified] index.html (14 B)\n\
+         [delete] old.html\n"
+    );
+    assert!(stderr(&output).contains("would upload 3, would delete 1"));
+    assert_eq!(server.requests("upload"), 0);
+    assert_eq!(server.requests("delete"), 0);
+    assert_eq!(server.get("index.html").unwrap(), b"<h1>old</h1>");
+    assert!(!site.path().join(".state").exists());
+}
+
+#[tokio::test]
+async fn json_output_reports_every_change() {
+    let (server, site) = setup().await;
+    server.put("old.html", "stale");
+    let path = site.path().to_str().unwrap();
+    let output = server.run(&["sync", path, "--output", "json"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["dry_run"], false);
+    assert_eq!(report["uploaded"].as_array().unwrap().len(), 3);
+    assert_eq!(report["uploaded"][0]["path"], "css/style.css");
+    assert_eq!(report["uploaded"][0]["reason"], "new");
+    assert_eq!(report["uploaded"][0]["size"], 18);
+    assert_eq!(
+        report["uploaded"][0]["hash"],
+        "ab19144fbc1a47311ac081826a55393f81857737"
+    );
+    assert_eq!(report["deleted"], serde_json::json!(["old.html"]));
+    assert_eq!(report["requests"], 4);
+    assert_eq!(report["errors"], serde_json::json!([]));
+}
+
+#[tokio::test]
+async fn json_output_reports_errors() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    let output =
+        server.run_with_key("wrong", &["sync", path, "--output", "json"]).await;
+    assert_eq!(output.status.code(), Some(1));
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["errors"][0]["error_type"], "invalid_auth");
+    assert_eq!(report["errors"][0]["status"], 403);
+}
+
+#[tokio::test]
+async fn uploads_are_split_by_file_count() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    let output = server.run(&["sync", path, "--batch-files", "2"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(server.requests("upload"), 2);
+    assert_eq!(server.paths(), ["css/style.css", "img/cat.png", "index.html"]);
+}
+
+#[tokio::test]
+async fn uploads_are_split_by_size() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    let args = ["sync", path, "--batch-size", "20", "--concurrency", "1"];
+    let output = server.run(&args).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(server.requests("upload"), 2);
+    assert_eq!(server.paths(), ["css/style.css", "img/cat.png", "index.html"]);
+}
+
+#[tokio::test]
+async fn large_files_are_streamed_intact() {
+    let (server, site) = setup().await;
+    let contents = (0..300_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    write(site.path(), "big.bin", &contents);
+    let path = site.path().to_str().unwrap();
+    let output = server.run(&["sync", path]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(server.get("big.bin").unwrap(), contents);
+    let output = server.run(&["sync", path]).await;
+    assert!(stderr(&output).contains("uploaded 0, deleted 0"));
+}
+
+#[tokio::test]
+async fn neocitiesignore_files_are_honoured() {
+    let (server, site) = setup().await;
+    write(site.path(), ".neocitiesignore", "node_modules/\n*.swp\n");
+    write(site.path(), "node_modules/lib/index.js", "module.exports = 1;");
+    write(site.path(), ".index.html.swp", "swap");
+    write(site.path(), "drafts/.neocitiesignore", "*.html\n!keep.html\n");
+    write(site.path(), "drafts/wip.html", "wip");
+    write(site.path(), "drafts/keep.html", "keep");
+    let output = server.run(&["sync", site.path().to_str().unwrap()]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        server.paths(),
+        ["css/style.css", "drafts/keep.html", "img/cat.png", "index.html"]
+    );
+}
+
+#[tokio::test]
+async fn ignored_remote_files_are_not_deleted() {
+    let (server, site) = setup().await;
+    write(site.path(), ".neocitiesignore", "guestbook/\n");
+    server.put("guestbook/entries.json", "[]");
+    let path = site.path().to_str().unwrap();
+    let output = server.run(&["sync", path]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 3, deleted 0"));
+    let output = server.run(&["sync", path]).await;
+    assert!(stderr(&output).contains("uploaded 0, deleted 0"));
+    assert_eq!(server.get("guestbook/entries.json").unwrap(), b"[]");
+}
+
+#[tokio::test]
+async fn exclude_and_include_globs() {
+    let (server, site) = setup().await;
+    server.put("old.png", "stale");
+    let path = site.path().to_str().unwrap();
+    let args = ["sync", path, "--exclude", "*.png", "--include", "img/cat.png"];
+    let output = server.run(&args).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        server.paths(),
+        ["css/style.css", "img/cat.png", "index.html", "old.png"]
+    );
+}
+
+#[tokio::test]
+async fn include_globs_reach_into_ignored_directories() {
+    let (server, site) = setup().await;
+    write(site.path(), ".neocitiesignore", "node_modules/\n");
+    write(site.path(), "node_modules/lib/x.js", "x");
+    write(site.path(), "node_modules/other.js", "other");
+    write(site.path(), "vendor/y.js", "y");
+    write(site.path(), "vendor/z.js", "z");
+    let path = site.path().to_str().unwrap();
+    let args = [
+        "sync",
+        path,
+        "--exclude",
+        "vendor/",
+        "--include",
+        "node_modules/lib/x.js",
+        "--include",
+        "vendor/y.js",
+    ];
+    let output = server.run(&args).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        server.paths(),
+        [
+            "css/style.css",
+            "img/cat.png",
+            "index.html",
+            "node_modules/lib/x.js",
+            "vendor/y.js"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn no_delete_keeps_remote_only_files() {
+    let (server, site) = setup().await;
+    server.put("guestbook.html", "hi");
+    let path = site.path().to_str().unwrap();
+    let output = server.run(&["sync", path, "--no-delete"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("[kept] guestbook.html"));
+    assert_eq!(server.requests("delete"), 0);
+    assert!(server.get("guestbook.html").is_some());
+}
+
+#[tokio::test]
+async fn kept_globs_are_never_deleted() {
+    let (server, site) = setup().await;
+    server.put("guestbook/2024.html", "hi");
+    server.put("old.html", "stale");
+    let path = site.path().to_str().unwrap();
+    let output = server.run(&["sync", path, "--keep", "guestbook/"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 3, deleted 1"));
+    assert!(server.get("guestbook/2024.html").is_some());
+    assert!(server.get("old.html").is_none());
+}
+
+#[tokio::test]
+async fn max_delete_aborts_large_deletions() {
+    let (server, site) = setup().await;
+    server.put("a.html", "a");
+    server.put("b.html", "b");
+    let path = site.path().to_str().unwrap();
+    let output = server.run(&["sync", path, "--max-delete", "1"]).await;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Refusing to delete."));
+    assert_eq!(server.requests("upload"), 0);
+    assert_eq!(server.requests("delete"), 0);
+    let output = server.run(&["sync", path, "--max-delete", "2"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 3, deleted 2"));
+}
+
+#[tokio::test]
+async fn transient_errors_are_retried() {
+    let (server, site) = setup().await;
+    server.fail("list", 0, StatusCode::SERVICE_UNAVAILABLE, "unavailable");
+    server.fail("list", 0, StatusCode::TOO_MANY_REQUESTS, "rate_limited");
+    let path = site.path().to_str().unwrap();
+    let output = server.run(&["sync", path, "--max-retry-delay", "0"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(server.requests("list"), 3);
+    assert_eq!(server.paths(), ["css/style.css", "img/cat.png", "index.html"]);
+}
+
+#[tokio::test]
+async fn errors_are_reported_after_retries_are_exhausted() {
+    let (server, site) = setup().await;
+    for _ in 0..3 {
+        server.fail("list", 0, StatusCode::BAD_GATEWAY, "bad_gateway");
+    }
+    let path = site.path().to_str().unwrap();
+    let args = ["sync", path, "--retries", "2", "--max-retry-delay", "0"];
+    let output = server.run(&args).await;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("bad_gateway (502 Bad Gateway)"));
+    assert_eq!(server.requests("list"), 3);
+}
+
+#[tokio::test]
+async fn negative_retry_delays_are_refused() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    let output = server.run(&["sync", path, "--max-retry-delay=-1"]).await;
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("expected a number of seconds of at least 0"));
+    assert!(server.paths().is_empty());
+}
+
+#[tokio::test]
+async fn network_errors_are_reported() {
+    let site = TempDir::new().unwrap();
+    let server = FakeNeocities::start().await;
+    let path = site.path().to_str().unwrap();
+    let args = ["sync", path, "--api-url", "http://127.0.0.1:1", "--retries", "0"];
+    let output = server.run(&args).await;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Network error:"));
+    assert!(!stderr(&output).contains("Panic!"));
+}
+
+#[tokio::test]
+async fn interrupted_sync_resumes_where_it_stopped() {
+    let (server, site) = setup().await;
+    server.fail("upload", 1, StatusCode::BAD_REQUEST, "too_large");
+    let path = site.path().to_str().unwrap();
+    let args = ["sync", path, "--batch-files", "1", "--concurrency", "1"];
+    let output = server.run(&args).await;
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(server.paths(), ["css/style.css"]);
+    let state = read_state(site.path());
+    assert!(state["files"]["css/style.css"]["uploaded_at"].is_u64());
+    assert!(state["files"].get("index.html").is_none());
+    let output = server.run(&args).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 2, deleted 0"));
+    assert!(!site.path().join(".state.tmp").exists());
+}
+
+#[tokio::test]
+async fn verify_remote_refuses_diverged_site() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    server.run(&["sync", path]).await;
+    server.put("index.html", "<h1>edited online</h1>");
+    write(site.path(), "index.html", "<h1>edited locally</h1>");
+    // The mode is optional, so it must not take the path after the flag.
+    let output = server.run(&["sync", "--verify-remote", path]).await;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("[diverged] index.html"));
+    assert!(stderr(&output).contains("The site was changed outside of neocities-sync."));
+    assert_eq!(server.get("index.html").unwrap(), b"<h1>edited online</h1>");
+    assert_eq!(server.requests("upload"), 1);
+}
+
+#[tokio::test]
+async fn verify_remote_warns_and_syncs() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    server.run(&["sync", path]).await;
+    server.put("index.html", "<h1>edited online</h1>");
+    let output = server.run(&["sync", path, "--verify-remote=warn"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("[diverged] index.html"));
+    assert!(stderr(&output).contains("uploaded 0, deleted 0"));
+}
+
+#[tokio::test]
+async fn verify_remote_reconciles_with_site() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    server.run(&["sync", path]).await;
+    server.put("index.html", "<h1>edited online</h1>");
+    server.remove("css/style.css");
+    server.put("extra.html", "<p>added online</p>");
+    let output = server.run(&["sync", path, "--verify-remote=reconcile"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("[diverged] extra.html"));
+    assert!(stderr(&output).contains("uploaded 2, deleted 0"));
+    assert_eq!(
+        server.paths(),
+        ["css/style.css", "extra.html", "img/cat.png", "index.html"]
+    );
+    assert_eq!(server.get("index.html").unwrap(), b"<h1>hello</h1>");
+}
+
+#[tokio::test]
+async fn verify_remote_skips_ignored_and_kept_paths() {
+    let (server, site) = setup().await;
+    write(site.path(), ".neocitiesignore", "drafts/\n");
+    let path = site.path().to_str().unwrap();
+    server.run(&["sync", path]).await;
+    server.put("drafts/post.html", "<p>draft</p>");
+    server.put("guestbook.html", "<p>hi</p>");
+    let args = ["sync", path, "--verify-remote", "--keep", "guestbook.html"];
+    let output = server.run(&args).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!stderr(&output).contains("[diverged]"));
+}
+
+#[tokio::test]
+async fn files_deleted_on_site_are_reconciled() {
+    let (server, site) = setup().await;
+    write(site.path(), "old.html", "<p>old</p>");
+    let path = site.path().to_str().unwrap();
+    server.run(&["sync", path]).await;
+    server.remove("img/cat.png");
+    server.remove("css/style.css");
+    std::fs::remove_file(site.path().join("img/cat.png")).unwrap();
+    std::fs::remove_file(site.path().join("old.html")).unwrap();
+    let output = server.run(&["sync", path]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("[reconciled] css/style.css"));
+    assert!(stderr(&output).contains("[reconciled] img/cat.png"));
+    assert!(stderr(&output).contains("uploaded 1, deleted 1"));
+    assert_eq!(server.paths(), ["css/style.css", "index.html"]);
+    assert_eq!(server.requests("delete"), 2);
+    let state = read_state(site.path());
+    assert!(state["files"].get("img/cat.png").is_none());
+    let output = server.run(&["sync", path]).await;
+    assert!(stderr(&output).contains("uploaded 0, deleted 0"));
+}
+
+#[tokio::test]
+async fn moved_files_are_renamed_instead_of_uploaded() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    server.run(&["sync", path]).await;
+    std::fs::create_dir(site.path().join("assets")).unwrap();
+    std::fs::rename(
+        site.path().join("img/cat.png"),
+        site.path().join("assets/cat.png"),
+    )
+    .unwrap();
+    let output = server.run(&["sync", path, "--dry-run"]).await;
+    assert_eq!(stdout(&output), "[rename] img/cat.png -> assets/cat.png\n");
+    let output = server.run(&["sync", path]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 0, deleted 0, renamed 1"));
+    assert_eq!(server.paths(), ["assets/cat.png", "css/style.css", "index.html"]);
+    assert_eq!(server.get("assets/cat.png").unwrap(), [0x89, b'P', b'N', b'G']);
+    assert_eq!(server.requests("upload"), 1);
+    let state = read_state(site.path());
+    assert!(state["files"].get("img/cat.png").is_none());
+    assert!(state["files"]["assets/cat.png"]["uploaded_at"].is_u64());
+    let output = server.run(&["sync", path]).await;
+    assert!(stderr(&output).contains("uploaded 0, deleted 0, renamed 0"));
+}
+
+#[tokio::test]
+async fn renames_are_sent_concurrently() {
+    let (server, site) = setup().await;
+    write(site.path(), "img/dog.png", "dog");
+    write(site.path(), "img/fox.png", "fox");
+    let path = site.path().to_str().unwrap();
+    server.run(&["sync", path]).await;
+    std::fs::rename(site.path().join("img"), site.path().join("images")).unwrap();
+    let output = server.run(&["sync", path, "--concurrency", "2"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 0, deleted 0, renamed 3"));
+    assert_eq!(server.requests("rename"), 3);
+    let state = read_state(site.path());
+    let files = state["files"].as_object().unwrap();
+    assert_eq!(
+        files.keys().collect::<Vec<_>>(),
+        [
+            "css/style.css",
+            "images/cat.png",
+            "images/dog.png",
+            "images/fox.png",
+            "index.html"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn renames_count_against_max_delete() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    server.run(&["sync", path]).await;
+    std::fs::rename(site.path().join("img/cat.png"), site.path().join("cat.png"))
+        .unwrap();
+    server.fail("rename", 0, StatusCode::BAD_REQUEST, "rename_error");
+    let output = server.run(&["sync", path, "--max-delete", "0"]).await;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Refusing to delete."));
+    assert_eq!(server.requests("rename"), 0);
+    assert_eq!(server.requests("delete"), 0);
+    assert!(server.get("img/cat.png").is_some());
+}
+
+#[tokio::test]
+async fn refused_renames_are_uploaded_instead() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    server.run(&["sync", path]).await;
+    std::fs::rename(site.path().join("img/cat.png"), site.path().join("cat.png"))
+        .unwrap();
+    server.fail("rename", 0, StatusCode::BAD_REQUEST, "rename_error");
+    let output = server.run(&["sync", path]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 1, deleted 1, renamed 0"));
+    assert_eq!(server.paths(), ["cat.png", "css/style.css", "index.html"]);
+}
+
+#[tokio::test]
+async fn root_index_is_never_renamed() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    server.run(&["sync", path]).await;
+    std::fs::rename(site.path().join("index.html"), site.path().join("home.html"))
+        .unwrap();
+    let output = server.run(&["sync", path]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 1"));
+    assert!(stderr(&output).contains("renamed 0"));
+    assert_eq!(server.requests("rename"), 0);
+    assert_eq!(server.get("index.html").unwrap(), b"<h1>hello</h1>");
+    assert_eq!(server.get("home.html").unwrap(), b"<h1>hello</h1>");
+}
+
+#[tokio::test]
+async fn remote_dir_scopes_the_sync() {
+    let (server, site) = setup().await;
+    server.put("index.html", "<h1>home</h1>");
+    server.put("blog/post.html", "<p>post</p>");
+    server.put("docs/old.html", "<p>old</p>");
+    let path = site.path().to_str().unwrap();
+    let output = server.run(&["sync", path, "--remote-dir", "/docs/"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 3, deleted 1"));
+    assert_eq!(
+        server.paths(),
+        [
+            "blog/post.html",
+            "docs/css/style.css",
+            "docs/img/cat.png",
+            "docs/index.html",
+            "index.html"
+        ]
+    );
+    assert_eq!(server.get("index.html").unwrap(), b"<h1>home</h1>");
+    let state = read_state(site.path());
+    assert_eq!(state["remote_dir"], "docs");
+    assert!(state["files"].get("index.html").is_some());
+    let output = server.run(&["sync", path, "--remote-dir", "docs"]).await;
+    assert!(stderr(&output).contains("uploaded 0, deleted 0"));
+}
+
+#[tokio::test]
+async fn state_file_of_another_remote_dir_is_refetched() {
+    let (server, site) = setup().await;
+    let path = site.path().to_str().unwrap();
+    server.run(&["sync", path, "--remote-dir", "docs"]).await;
+    let output = server.run(&["sync", path, "--remote-dir", "v2"]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 3, deleted 0"));
+    assert_eq!(server.paths().len(), 6);
+    let output = server.run(&["sync", path]).await;
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("uploaded 0, deleted 3, renamed 3"));
+    assert!(read_state(site.path()).get("remote_dir").is_none());
+}